@@ -1,106 +1,438 @@
 use super::*;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct InvoiceParams {
-    #[serde(rename = "0")]
-    amount_msat: u64,
-    #[serde(rename = "1")]
-    description: String,
-    #[serde(rename = "2")]
-    label: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct RpcCommand {
-    id: String,
-    jsonrpc: String,
-    method: String,
-    params: InvoiceParams,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ConnectHookCall {
-    rpc_command: RpcCommand,
-}
+// LSPS2 opening fee params are only promised for a limited time, so keep JIT invoices short lived
+const JIT_INVOICE_EXPIRY_SECS: u64 = 3600;
+// label prefix of the placeholder invoices taking lightningd's created_index for replacements
+const CREATED_INDEX_PLACEHOLDER_PREFIX: &str = "kickstart-created-index-";
+// a mint's invoice has to stay payable at least this long, and without a requested expiry
+// at most as long as lightningd's default expiry of a week
+const MIN_MINT_INVOICE_EXPIRY_SECS: u64 = 60;
+const MAX_MINT_INVOICE_EXPIRY_SECS: u64 = 604_800;
 
 // main handler that hooks into the lightning-invoice RPC command
 pub async fn rpc_command_handler(
-    p: Plugin<Arc<Mutex<EcashWallet>>>,
+    p: Plugin<PluginState>,
     v: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
-    let rpc_command: Option<ConnectHookCall> = serde_json::from_value(v).ok();
-    // we continue if it is the "invoice" command else we return
-    if let Some(rpc_call) = rpc_command {
-        debug!("Got a invoice hook call: {:?}", rpc_call.rpc_command.params);
-
-        // fetch the balances
-        let inbound_liq_msat = (get_available_inbound_liquidity().await? as f64 * 0.9) as u64; // 0.9 is a buffer factor
-        let ecash_balance_sat = p.state().lock().await.get_total_balance().await?;
-        p.state().lock().await.last_balance = ecash_balance_sat; // https://www.youtube.com/watch?v=dQw4w9WgXcQ
-        debug!(
-            "Inbound liquidity: {} | Ecash balance: {}",
-            inbound_liq_msat, ecash_balance_sat
+    let method = v["rpc_command"]["method"].as_str().unwrap_or_default();
+    if method != "invoice" {
+        return Ok(
+            invoice_rpc_handler(&p.state().invoices, method, &v["rpc_command"]["params"]).await?,
         );
+    }
+    let config = p.state().config();
+    if !config.fallback_enabled {
+        return Ok(continue_invoice(&v["rpc_command"]));
+    }
+    // invalid calls are left to lightningd to answer with its own error
+    let params = match InvoiceParams::parse(&v["rpc_command"]["params"]) {
+        Ok(params) => params,
+        // lightningd doesn't know our own param
+        Err(e)
+            if v["rpc_command"]["params"]
+                .get(FALLBACK_OVERRIDE_PARAM)
+                .is_some() =>
+        {
+            return Ok(error_response(-32602, e.to_string()));
+        }
+        Err(e) => {
+            debug!("Not handling invoice call: {}", e);
+            return Ok(json!({"result": "continue"}));
+        }
+    };
+    debug!("Got a invoice hook call: {:?}", params);
+    // any amount invoices can't be checked against the inbound liquidity
+    let Some(amount_msat) = params.amount_msat else {
+        return Ok(continue_invoice(&v["rpc_command"]));
+    };
 
-        if inbound_liq_msat < rpc_call.rpc_command.params.amount_msat {
-            // replace invoice with cashu invoice
-            let cashu_invoice = match p
-                .state()
-                .lock()
-                .await
-                .create_lightning_invoice(rpc_call.rpc_command.params.amount_msat / 1000)
-                .await
-            {
-                Ok(request) => request,
-                Err(e) => {
-                    error!("Error creating cashu invoice (mint probably doesn't like high amounts): {}", e);
-                    return Ok(json!({"return": {
-                        "error": {
-                            "message": "Error creating cashu invoice and you have too low inbound liquidity,
-                            probably too low/high amount for mint (use msat!)",
-                            "code": 1
-                        }
-                    }}));
-                }
-            };
-            debug!("Cashu invoice: {}", cashu_invoice.bolt11);
-            return Ok(json!({
-                "return": {
-                    "result": {
-                        "bolt11": cashu_invoice.bolt11,
-                        "created_index": 999,  // dummy
-                        "expires_at": cashu_invoice.expiry,
-                        "payment_hash": "0b02490f82526f7ea932b8c2cd265be6e9d6a0eb043bd560efe989cd28e4717",  // dummy -> "cashu"
-                        "paymetn_secret": "000" // dummy
+    let receivable_msat = get_receivable_capacity(&params.expose_private_channels)
+        .await?
+        .total_msat;
+    // the balance as of the last refresh, the hook must not wait for wallet operations
+    let ecash_balance_sat = p.state().wallet.lock().await.last_balance;
+    debug!(
+        "Inbound liquidity: {} | Ecash balance: {}",
+        receivable_msat, ecash_balance_sat
+    );
+
+    let decision = config.liquidity_policy.decide(&FallbackRequest {
+        amount_msat,
+        label: &params.label,
+        receivable_msat,
+        fallback_override: params.fallback_override,
+    });
+    info!(
+        "Invoice {}: {} ({})",
+        params.label,
+        if decision.fallback {
+            "replacing"
+        } else {
+            "not replacing"
+        },
+        decision.reason
+    );
+    if !decision.fallback {
+        return Ok(continue_invoice(&v["rpc_command"]));
+    }
+    if let Err(response) = p.state().invoices.check_label_unused(&params.label).await {
+        return Ok(response);
+    }
+
+    // replace invoice with one routed over a just-in-time channel from the LSP
+    if let (true, Some(lsps2)) = (config.fallback.use_jit(amount_msat), &p.state().lsps2) {
+        let expiry_secs = params.expiry.unwrap_or(JIT_INVOICE_EXPIRY_SECS);
+        let result = match params.check_replaceable(true) {
+            Ok(()) if expiry_secs > JIT_INVOICE_EXPIRY_SECS => Err(anyhow!(
+                "expiry of JIT channel invoices can't exceed {} seconds",
+                JIT_INVOICE_EXPIRY_SECS
+            )),
+            Ok(()) => {
+                lsps2
+                    .create_jit_invoice(amount_msat, &params, expiry_secs)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(bolt11) => {
+                debug!("JIT channel invoice: {}", bolt11);
+                return Ok(invoice_response(&p, &params, &decision, &bolt11, None).await);
+            }
+            // hybrid mode can still try the mint
+            Err(e) if config.fallback.mode == FallbackMode::Hybrid => {
+                warn!("Error buying JIT channel, falling back to ecash: {}", e);
+            }
+            Err(e) => {
+                error!("Error buying JIT channel: {}", e);
+                return Ok(json!({"return": {
+                    "error": {
+                        "message": format!("Error buying JIT channel from LSP and you have too low inbound liquidity: {}", e),
+                        "code": 1
                     }
+                }}));
+            }
+        }
+    }
+
+    if let Err(e) = params.check_replaceable(false) {
+        return Ok(json!({"return": {
+            "error": {
+                "message": format!("Too low inbound liquidity for this invoice: {}", e),
+                "code": 1
+            }
+        }}));
+    }
+    // replace invoice with cashu invoice, mints only take whole sats so the payer pays up to 999 msat more
+    let amount_sat = amount_msat.div_ceil(1000);
+    let cashu_invoice = match p
+        .state()
+        .wallet
+        .lock()
+        .await
+        .create_lightning_invoice(amount_sat, Some(&params.description))
+        .await
+    {
+        Ok(request) => request,
+        Err(e) => {
+            error!(
+                "Error creating cashu invoice (mint probably doesn't like high amounts): {}",
+                e
+            );
+            return Ok(json!({"return": {
+                "error": {
+                    "message": "Error creating cashu invoice and you have too low inbound liquidity,
+                    probably too low/high amount for mint (use msat!)",
+                    "code": 1
                 }
-            }));
+            }}));
         }
+    };
+    // the mint chooses the expiry, an invoice outliving the requested expiry is refused
+    // the requested expiry is user input and may be anything up to u64::MAX
+    let max_expires_at =
+        unix_time().saturating_add(params.expiry.unwrap_or(MAX_MINT_INVOICE_EXPIRY_SECS));
+    if let Err(mismatch) =
+        check_mint_invoice(&cashu_invoice, amount_sat, &config.network, max_expires_at)
+    {
+        error!(
+            "Mint {} returned an invalid invoice {}: {} is {}, expected {}",
+            cashu_invoice.mint_url,
+            cashu_invoice.bolt11,
+            mismatch.check,
+            mismatch.actual,
+            mismatch.expected
+        );
+        return Ok(mismatch.error_response(&cashu_invoice));
     }
-    Ok(json!({"result": "continue"}))
+    debug!("Cashu invoice: {}", cashu_invoice.bolt11);
+    Ok(invoice_response(
+        &p,
+        &params,
+        &decision,
+        &cashu_invoice.bolt11,
+        Some(&cashu_invoice),
+    )
+    .await)
 }
 
-// fetches the total available inbound liquidity in msat
-async fn get_available_inbound_liquidity() -> Result<u64> {
-    let request = ListfundsRequest { spent: None };
-    let response: Response = send_rpc_request(request.into()).await?;
-    let listfunds_channels = match response {
-        Response::ListFunds(funds) => funds.channels,
+// a check of the mint's invoice that failed, the invoice is not handed out then
+#[derive(Debug)]
+struct MintInvoiceMismatch {
+    check: &'static str,
+    expected: String,
+    actual: String,
+}
+
+impl MintInvoiceMismatch {
+    fn new(check: &'static str, expected: impl ToString, actual: impl ToString) -> Self {
+        MintInvoiceMismatch {
+            check,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }
+    }
+
+    fn error_response(&self, request: &PaymentRequest) -> serde_json::Value {
+        json!({"return": {"error": {
+            "code": 1,
+            "message": format!(
+                "The mint's invoice doesn't match the request: {} is {}, expected {}",
+                self.check, self.actual, self.expected
+            ),
+            "data": {
+                "mint_url": request.mint_url,
+                "mint_quote_id": request.mint_quote_id,
+                "bolt11": request.bolt11,
+                "check": self.check,
+                "expected": self.expected,
+                "actual": self.actual,
+            },
+        }}})
+    }
+}
+
+// a buggy or malicious mint must not get payers to pay a different invoice than requested
+fn check_mint_invoice(
+    request: &PaymentRequest,
+    amount_sat: u64,
+    network: &Currency,
+    max_expires_at: u64,
+) -> Result<(), MintInvoiceMismatch> {
+    let invoice = Bolt11Invoice::from_str(&request.bolt11)
+        .map_err(|e| MintInvoiceMismatch::new("bolt11", "a valid invoice", e))?;
+    if invoice.currency() != *network {
+        return Err(MintInvoiceMismatch::new(
+            "network",
+            format!("{:?}", network),
+            format!("{:?}", invoice.currency()),
+        ));
+    }
+    let amount_msat = amount_sat * 1000;
+    if invoice.amount_milli_satoshis() != Some(amount_msat) {
+        return Err(MintInvoiceMismatch::new(
+            "amount_msat",
+            amount_msat,
+            invoice
+                .amount_milli_satoshis()
+                .map_or("any".to_string(), |amount| amount.to_string()),
+        ));
+    }
+    let expires_at = invoice
+        .expires_at()
+        .ok_or_else(|| {
+            MintInvoiceMismatch::new(
+                "expires_at",
+                format!("at most {}", max_expires_at),
+                "out of range",
+            )
+        })?
+        .as_secs();
+    let min_expires_at = unix_time() + MIN_MINT_INVOICE_EXPIRY_SECS;
+    if expires_at < min_expires_at {
+        return Err(MintInvoiceMismatch::new(
+            "expires_at",
+            format!("at least {}", min_expires_at),
+            expires_at,
+        ));
+    }
+    if expires_at > max_expires_at {
+        return Err(MintInvoiceMismatch::new(
+            "expires_at",
+            format!("at most {}", max_expires_at),
+            expires_at,
+        ));
+    }
+    // only mints supporting descriptions got one
+    if let Some(description) = &request.description {
+        let actual = match invoice.description() {
+            Bolt11InvoiceDescription::Direct(actual) => actual.to_string(),
+            Bolt11InvoiceDescription::Hash(hash) => format!("hash {}", hash.0),
+        };
+        if actual != *description {
+            return Err(MintInvoiceMismatch::new("description", description, actual));
+        }
+    }
+    Ok(())
+}
+
+// passes the invoice call on to lightningd, without our own param it doesn't know
+fn continue_invoice(rpc_command: &serde_json::Value) -> serde_json::Value {
+    let mut request = rpc_command.clone();
+    match request["params"].as_object_mut() {
+        Some(params) if params.remove(FALLBACK_OVERRIDE_PARAM).is_some() => {
+            json!({"replace": request})
+        }
+        _ => json!({"result": "continue"}),
+    }
+}
+
+// answers the invoice call like lightningd would, with the fields decoded from the replacement,
+// which is recorded so listinvoices and friends know about it
+async fn invoice_response(
+    p: &Plugin<PluginState>,
+    params: &InvoiceParams,
+    decision: &FallbackDecision,
+    bolt11: &str,
+    mint_quote: Option<&PaymentRequest>,
+) -> serde_json::Value {
+    match record_replacement(p, params, decision, bolt11, mint_quote).await {
+        Ok((record, payment_secret)) => json!({"return": {"result": {
+            "bolt11": record.bolt11,
+            "payment_hash": record.payment_hash,
+            "payment_secret": payment_secret,
+            "expires_at": record.expires_at,
+            "created_index": record.created_index,
+        }}}),
+        Err(e) => {
+            error!("Error recording replacement invoice {}: {}", bolt11, e);
+            error_response(1, format!("Error recording replacement invoice: {}", e))
+        }
+    }
+}
+
+async fn record_replacement(
+    p: &Plugin<PluginState>,
+    params: &InvoiceParams,
+    decision: &FallbackDecision,
+    bolt11: &str,
+    mint_quote: Option<&PaymentRequest>,
+) -> Result<(InvoiceRecord, String)> {
+    let invoice = Bolt11Invoice::from_str(bolt11).map_err(|e| anyhow!("{}", e))?;
+    let expires_at = invoice
+        .expires_at()
+        .ok_or(anyhow!("Invoice expiry out of range"))?
+        .as_secs();
+    let record = InvoiceRecord {
+        label: params.label.clone(),
+        bolt11: bolt11.to_string(),
+        payment_hash: invoice.payment_hash().to_string(),
+        amount_msat: invoice
+            .amount_milli_satoshis()
+            .or(params.amount_msat)
+            .unwrap_or_default(),
+        description: Some(params.description.clone()),
+        created_index: reserve_created_index(&invoice.payment_hash().to_string()).await?,
+        expires_at,
+        mint_quote_id: mint_quote.map(|quote| quote.mint_quote_id.clone()),
+        mint_url: mint_quote.map(|quote| quote.mint_url.clone()),
+        kickstart_pay_index: None,
+        amount_received_msat: None,
+        paid_at: None,
+        fallback_reason: Some(decision.reason.clone()),
+    };
+    p.state().invoices.insert(&record).await?;
+    Ok((record, hex::encode(invoice.payment_secret().0)))
+}
+
+// lightningd doesn't know replaced invoices, so they get the created_index of a placeholder
+// invoice that is deleted right away, lightningd never hands out the same index again
+async fn reserve_created_index(payment_hash: &str) -> Result<u64> {
+    let label = format!("{}{}", CREATED_INDEX_PLACEHOLDER_PREFIX, payment_hash);
+    // any amount invoices pass our own hook unchanged
+    let request = InvoiceRequest {
+        amount_msat: AmountOrAny::Any,
+        label: label.clone(),
+        description: "kickstart created_index placeholder".to_string(),
+        // expires soon in case deleting it fails
+        expiry: Some(MIN_MINT_INVOICE_EXPIRY_SECS),
+        fallbacks: None,
+        preimage: None,
+        exposeprivatechannels: None,
+        cltv: None,
+        deschashonly: None,
+    };
+    let created_index = match send_rpc_request(request.into()).await? {
+        Response::Invoice(response) => response
+            .created_index
+            .ok_or(anyhow!("No created_index in invoice response"))?,
         _ => return Err(anyhow!("Unexpected response")),
     };
+    let request = DelinvoiceRequest {
+        label: label.clone(),
+        status: DelinvoiceStatus::UNPAID,
+        desconly: None,
+    };
+    if let Err(e) = send_rpc_request(request.into()).await {
+        warn!("Error deleting placeholder invoice {}: {}", label, e);
+    }
+    Ok(created_index)
+}
 
-    let total_inbound_liquidity: u64 = listfunds_channels
-        .iter()
-        .map(|channel| {
-            if channel.connected {
-                channel.amount_msat.msat() - channel.our_amount_msat.msat()
-            } else {
-                0
-            }
-        })
-        .sum();
+// what our channels can receive right now
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReceivableCapacity {
+    // over all usable channels, payers split larger payments into parts (MPP)
+    pub total_msat: u64,
+    // the largest payment a single channel can take without splitting
+    pub largest_channel_msat: u64,
+}
+
+// receivable_msat of listpeerchannels already accounts for reserves and in-flight HTLCs,
+// channels count once they can forward to us, private channels count only if they may be
+// used in route hints
+pub async fn get_receivable_capacity(
+    expose_private_channels: &ExposePrivateChannels,
+) -> Result<ReceivableCapacity> {
+    let request = ListpeerchannelsRequest {
+        id: None,
+        short_channel_id: None,
+    };
+    let channels = match send_rpc_request(request.into()).await? {
+        Response::ListPeerChannels(response) => response.channels,
+        _ => return Err(anyhow!("Unexpected response")),
+    };
+    let mut capacity = ReceivableCapacity::default();
+    for channel in channels {
+        if !channel.peer_connected || channel.state != ChannelState::CHANNELD_NORMAL {
+            continue;
+        }
+        let short_channel_id = channel.short_channel_id.map(|scid| scid.to_string());
+        if channel.private == Some(true)
+            && !expose_private_channels.allows(short_channel_id.as_deref())
+        {
+            continue;
+        }
+        let receivable_msat = channel.receivable_msat.map_or(0, |amount| amount.msat());
+        capacity.total_msat += receivable_msat;
+        // a payment split over several HTLCs can use all of it, a single part can't exceed
+        // what the peer is willing to forward in one HTLC
+        let single_part_msat = match channel.updates.and_then(|updates| updates.remote) {
+            Some(remote) => receivable_msat.min(remote.htlc_maximum_msat.msat()),
+            None => receivable_msat,
+        };
+        capacity.largest_channel_msat = capacity.largest_channel_msat.max(single_part_msat);
+    }
+    Ok(capacity)
+}
 
-    Ok(total_inbound_liquidity)
+// msat amounts are numbers or strings with msat suffix depending on the lightningd version
+pub fn msat_value(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(amount) => amount.as_u64(),
+        serde_json::Value::String(amount) => amount.trim_end_matches("msat").parse().ok(),
+        _ => None,
+    }
 }
 
 // connects to the LSP node as we don't have a public IP to connect to and
@@ -131,14 +463,6 @@ pub async fn connect_and_get_pk(lsp_host: &str, lsp_port: u16, lsp_id: &str) ->
     Ok(public_key)
 }
 
-// can't init rpc client upfront because the socket is only available after plugin setup
-async fn send_rpc_request(request: Request) -> Result<Response> {
-    let path = Path::new("./lightning-rpc");
-    let mut rpc = ClnRpc::new(path).await?;
-    let response = rpc.call(request).await?;
-    Ok(response)
-}
-
 // sample logs ----------------------
 // Got a connect hook call: {"rpc_command":{"id":"init/offers:listconfigs#6","jsonrpc":"2.0","method":"listconfigs","params":{"config":"i-promise-to-fix-broken-api-user"}}}
 // Got a connect hook call: {"rpc_command":{"id":"init/bookkeeper:listconfigs#0","jsonrpc":"2.0","method":"listconfigs","params":{"config":"i-promise-to-fix-broken-api-user"}}}
@@ -155,3 +479,112 @@ async fn send_rpc_request(request: Request) -> Result<Response> {
 // }
 
 // Got a connect hook call: {"rpc_command":{"id":"cli:invoice#2985","jsonrpc":"2.0","method":"invoice","params":[1000,"desc","lab"]}}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // an invoice created now, signed with a fixed throwaway key
+    pub(crate) fn signed_invoice(
+        network: Currency,
+        amount_msat: u64,
+        expiry_secs: u64,
+        description: &str,
+    ) -> String {
+        signed_invoice_at(network, amount_msat, unix_time(), expiry_secs, description)
+    }
+
+    pub(crate) fn signed_invoice_at(
+        network: Currency,
+        amount_msat: u64,
+        created_at: u64,
+        expiry_secs: u64,
+        description: &str,
+    ) -> String {
+        let key = secp256k1::SecretKey::from_slice(&[42; 32]).unwrap();
+        InvoiceBuilder::new(network)
+            .description(description.to_string())
+            .payment_hash(sha256::Hash::hash(&[0; 32]))
+            .payment_secret(PaymentSecret([0; 32]))
+            .amount_milli_satoshis(amount_msat)
+            .duration_since_epoch(Duration::from_secs(created_at))
+            .min_final_cltv_expiry_delta(18)
+            .expiry_time(Duration::from_secs(expiry_secs))
+            .build_signed(|hash| secp256k1::Secp256k1::new().sign_ecdsa_recoverable(hash, &key))
+            .unwrap()
+            .to_string()
+    }
+
+    fn mint_request(bolt11: String, description: Option<&str>) -> PaymentRequest {
+        PaymentRequest {
+            bolt11,
+            mint_quote_id: "quote".to_string(),
+            mint_url: "https://mint.example".to_string(),
+            amount_sat: 2,
+            expiry: unix_time() + 3600,
+            description: description.map(str::to_string),
+        }
+    }
+
+    fn check(
+        bolt11: String,
+        description: Option<&str>,
+        amount_sat: u64,
+        max_expiry_secs: u64,
+    ) -> Result<(), &'static str> {
+        check_mint_invoice(
+            &mint_request(bolt11, description),
+            amount_sat,
+            &Currency::Bitcoin,
+            unix_time().saturating_add(max_expiry_secs),
+        )
+        .map_err(|mismatch| mismatch.check)
+    }
+
+    #[test]
+    fn requires_amount_rounded_up_to_whole_sats() {
+        let amount_sat = 1500u64.div_ceil(1000);
+        let invoice = |amount_msat| signed_invoice(Currency::Bitcoin, amount_msat, 3600, "d");
+        assert_eq!(check(invoice(2000), None, amount_sat, 3600), Ok(()));
+        assert_eq!(
+            check(invoice(1500), None, amount_sat, 3600),
+            Err("amount_msat")
+        );
+        assert_eq!(
+            check(invoice(3000), None, amount_sat, 3600),
+            Err("amount_msat")
+        );
+    }
+
+    #[test]
+    fn requires_our_network() {
+        let invoice = signed_invoice(Currency::Signet, 2000, 3600, "d");
+        assert_eq!(check(invoice, None, 2, 3600), Err("network"));
+    }
+
+    #[test]
+    fn requires_expiry_within_bounds() {
+        let invoice = |expiry_secs| signed_invoice(Currency::Bitcoin, 2000, expiry_secs, "d");
+        assert_eq!(check(invoice(30), None, 2, 3600), Err("expires_at"));
+        assert_eq!(check(invoice(3600), None, 2, 600), Err("expires_at"));
+        assert_eq!(check(invoice(600), None, 2, 3600), Ok(()));
+        // without a requested expiry the invoice may live up to a week
+        let week = MAX_MINT_INVOICE_EXPIRY_SECS;
+        assert_eq!(check(invoice(week), None, 2, week), Ok(()));
+        assert_eq!(check(invoice(2 * week), None, 2, week), Err("expires_at"));
+        // a huge requested expiry caps nothing instead of overflowing
+        assert_eq!(check(invoice(2 * week), None, 2, u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn requires_requested_description() {
+        let invoice = || signed_invoice(Currency::Bitcoin, 2000, 3600, "tea");
+        assert_eq!(check(invoice(), Some("tea"), 2, 3600), Ok(()));
+        assert_eq!(
+            check(invoice(), Some("coffee"), 2, 3600),
+            Err("description")
+        );
+        // mints without description support got none
+        assert_eq!(check(invoice(), None, 2, 3600), Ok(()));
+    }
+}
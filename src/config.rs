@@ -0,0 +1,467 @@
+use super::*;
+
+// plugin options, settable in the lightningd config file and shown by listconfigs,
+// the dynamic ones can be changed at runtime with setconfig
+
+const DEFAULT_TARGET_CHANNEL_SIZE_SAT: u64 = 1_000_000;
+const DEFAULT_LIQUIDITY_BUFFER_FACTOR: f64 = 0.9;
+const DEFAULT_MINT_POLL_INTERVAL_SECS: u64 = 10;
+const DEFAULT_CHANNEL_CHECK_INTERVAL_SECS: u64 = 15;
+const DEFAULT_CHANNEL_COOLDOWN_SECS: u64 = 3600;
+const DEFAULT_MAX_CONCURRENT_ORDERS: u64 = 1;
+const DEFAULT_MAX_CHANNEL_FEE_PERCENT: f64 = 10.0;
+const DEFAULT_JIT_MIN_AMOUNT_SAT: u64 = 100_000;
+
+// lightningd doesn't support dynamic multi options, so this is a comma separated list
+pub const OPT_MINT_URL: StringConfigOption = ConfigOption::new_str_no_default(
+    "kickstart-mint-url",
+    "comma separated ecash mints for fallback invoices in order of preference, each optionally \
+     with its own balance limit as url=max_sat (default https://mint.coinos.io on mainnet)",
+);
+pub const OPT_MINT_MAX_BALANCE_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-mint-max-balance-sat",
+    "most ecash to hold on a single mint incl. pending invoices, further invoices fail over \
+     to the next mint (default unlimited)",
+);
+pub const OPT_LSP_NODE: StringArrayConfigOption = ConfigOption::new_str_arr_no_default(
+    "kickstart-lsp-node",
+    "LSPS1 node (pubkey@host:port) to buy channels from over BOLT8 custom messages",
+);
+pub const OPT_LSP_URL: StringArrayConfigOption = ConfigOption::new_str_arr_no_default(
+    "kickstart-lsp-url",
+    "LSPS1 REST API to buy channels from (default https://lsps1.lnolymp.us on mainnet, \
+     https://mutinynet-lsps1.lnolymp.us on signet)",
+);
+pub const OPT_LSPS2_NODE: StringConfigOption = ConfigOption::new_str_no_default(
+    "kickstart-lsps2-node",
+    "LSPS2 node (pubkey@host:port) selling JIT channels (default first kickstart-lsp-node)",
+);
+pub const OPT_TARGET_CHANNEL_SIZE_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-target-channel-size-sat",
+    "size of the channel to buy from the LSP (default 1000000)",
+);
+pub const OPT_LIQUIDITY_BUFFER_FACTOR: StringConfigOption = ConfigOption::new_str_no_default(
+    "kickstart-liquidity-buffer-factor",
+    "share of the inbound liquidity considered usable for receiving (default 0.9)",
+);
+pub const OPT_LIQUIDITY_MARGIN_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-liquidity-margin-sat",
+    "inbound liquidity kept free on top of the buffer factor (default 0)",
+);
+pub const OPT_FALLBACK_MIN_AMOUNT_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-fallback-min-amount-sat",
+    "smallest invoice to replace, smaller ones are left to lightningd (default none)",
+);
+pub const OPT_FALLBACK_MAX_AMOUNT_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-fallback-max-amount-sat",
+    "largest invoice to replace, larger ones are left to lightningd (default none)",
+);
+pub const OPT_FALLBACK_OPT_OUT_LABEL_PREFIX: StringArrayConfigOption =
+    ConfigOption::new_str_arr_no_default(
+        "kickstart-fallback-opt-out-label-prefix",
+        "invoices with labels starting with this prefix are never replaced, can be given multiple times",
+    );
+pub const OPT_MINT_POLL_INTERVAL_SECS: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-mint-poll-interval-secs",
+    "seconds between polls of pending mint quotes, backing off while nothing is paid (default 10)",
+);
+pub const OPT_CHANNEL_CHECK_INTERVAL_SECS: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-channel-check-interval-secs",
+    "seconds between checks if a channel can be bought, balance and channel changes trigger \
+     a check right away (default 15)",
+);
+pub const OPT_CHANNEL_COOLDOWN_SECS: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-channel-cooldown-secs",
+    "seconds without channel purchases after an LSP order ended or a purchase failed (default 3600)",
+);
+pub const OPT_MAX_CONCURRENT_ORDERS: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-max-concurrent-orders",
+    "most LSP orders waiting for their channel at the same time (default 1)",
+);
+pub const OPT_MAX_CHANNEL_FEE_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-max-channel-fee-sat",
+    "most an LSP may charge for a channel, orders above are not paid (default unlimited)",
+);
+pub const OPT_MAX_CHANNEL_FEE_PERCENT: StringConfigOption = ConfigOption::new_str_no_default(
+    "kickstart-max-channel-fee-percent",
+    "most an LSP may charge for a channel in percent of its size, orders above are not paid (default 10)",
+);
+pub const OPT_FALLBACK_MODE: StringConfigOption = ConfigOption::new_str_no_default(
+    "kickstart-fallback-mode",
+    "ecash, jit or hybrid: how to receive payments without inbound liquidity (default ecash)",
+);
+pub const OPT_JIT_MIN_AMOUNT_SAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "kickstart-jit-min-amount-sat",
+    "smallest payment to use a JIT channel for in hybrid mode (default 100000)",
+);
+pub const OPT_ENABLE_FALLBACK: BooleanConfigOption = ConfigOption::new_bool_no_default(
+    "kickstart-enable-fallback",
+    "replace invoices if inbound liquidity is too low (default true)",
+);
+pub const OPT_ENABLE_CHANNEL_MANAGER: BooleanConfigOption = ConfigOption::new_bool_no_default(
+    "kickstart-enable-channel-manager",
+    "buy a channel from the LSP once the ecash balance suffices (default true)",
+);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MintConfig {
+    pub url: String,
+    pub max_balance_sat: Option<u64>,
+}
+
+// defaults depend on the node's network so no invoices of another network are handed out,
+// there are none for testnet and regtest
+fn default_mint_url(network: &Currency) -> Option<&'static str> {
+    match network {
+        Currency::Bitcoin => Some("https://mint.coinos.io"),
+        _ => None,
+    }
+}
+
+fn default_lsp_url(network: &Currency) -> Option<&'static str> {
+    match network {
+        Currency::Bitcoin => Some("https://lsps1.lnolymp.us"),
+        Currency::Signet => Some("https://mutinynet-lsps1.lnolymp.us"),
+        _ => None,
+    }
+}
+
+// the network of our node, mints and LSPs must issue invoices for it
+pub async fn node_network() -> Result<Currency> {
+    let response = send_rpc_request(GetinfoRequest {}.into()).await?;
+    let network = match response {
+        Response::Getinfo(info) => info.network,
+        _ => return Err(anyhow!("Unexpected response")),
+    };
+    match network.as_str() {
+        "bitcoin" => Ok(Currency::Bitcoin),
+        "testnet" => Ok(Currency::BitcoinTestnet),
+        "signet" => Ok(Currency::Signet),
+        "regtest" => Ok(Currency::Regtest),
+        _ => Err(anyhow!("Unsupported network: {}", network)),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub network: Currency,
+    pub mints: Vec<MintConfig>,
+    pub lsp_nodes: Vec<String>,
+    pub lsp_urls: Vec<String>,
+    pub lsps2_node: Option<String>,
+    pub target_channel_size_sat: u64,
+    pub liquidity_policy: ThresholdPolicy,
+    pub mint_poll_interval: Duration,
+    pub channel_check_interval: Duration,
+    pub channel_cooldown: Duration,
+    pub max_concurrent_orders: usize,
+    pub max_channel_fee_sat: Option<u64>,
+    pub max_channel_fee_percent: f64,
+    pub fallback: FallbackPolicy,
+    pub fallback_enabled: bool,
+    pub channel_manager_enabled: bool,
+    pub lightning_dir: PathBuf,
+}
+
+impl Config {
+    // reads the plugin options, falling back to the deprecated env vars
+    pub fn from_plugin(
+        plugin: &ConfiguredPlugin<PluginState, Stdin, Stdout>,
+        network: Currency,
+    ) -> Result<Self> {
+        let default_max_balance_sat = int_option(
+            plugin.option(&OPT_MINT_MAX_BALANCE_SAT)?,
+            OPT_MINT_MAX_BALANCE_SAT.name,
+        )?;
+        let mint_list = match plugin.option(&OPT_MINT_URL)? {
+            Some(mints) => mints,
+            None => deprecated_env("MINT_URL", OPT_MINT_URL.name)
+                .or(default_mint_url(&network).map(str::to_string))
+                .ok_or(anyhow!(
+                    "No default mint for {:?}, set {}",
+                    network,
+                    OPT_MINT_URL.name
+                ))?,
+        };
+        let mints = parse_mints(&mint_list, default_max_balance_sat)?;
+        let lsp_nodes = match plugin.option(&OPT_LSP_NODE)? {
+            Some(nodes) if !nodes.is_empty() => nodes,
+            _ => deprecated_env_list("LSP_NODE_URI", OPT_LSP_NODE.name).unwrap_or_default(),
+        };
+        let mut lsp_urls = match plugin.option(&OPT_LSP_URL)? {
+            Some(urls) if !urls.is_empty() => urls,
+            _ => deprecated_env_list("LSP_URL", OPT_LSP_URL.name).unwrap_or_default(),
+        };
+        let channel_manager_enabled = plugin.option(&OPT_ENABLE_CHANNEL_MANAGER)?.unwrap_or(true);
+        if lsp_nodes.is_empty() && lsp_urls.is_empty() {
+            match default_lsp_url(&network) {
+                Some(url) => lsp_urls.push(url.to_string()),
+                None if channel_manager_enabled => {
+                    return Err(anyhow!(
+                        "No default LSP for {:?}, set {} or {}",
+                        network,
+                        OPT_LSP_NODE.name,
+                        OPT_LSP_URL.name
+                    ))
+                }
+                None => {}
+            }
+        }
+        let lsps2_node = plugin
+            .option(&OPT_LSPS2_NODE)?
+            .or_else(|| deprecated_env("LSPS2_NODE_URI", OPT_LSPS2_NODE.name))
+            .or_else(|| lsp_nodes.first().cloned());
+
+        let target_channel_size_sat = int_option_or_env(
+            plugin.option(&OPT_TARGET_CHANNEL_SIZE_SAT)?,
+            "TARGET_CHANNEL_SIZE_SAT",
+            OPT_TARGET_CHANNEL_SIZE_SAT.name,
+        )?
+        .unwrap_or(DEFAULT_TARGET_CHANNEL_SIZE_SAT);
+        let liquidity_policy = ThresholdPolicy {
+            buffer_factor: match plugin.option(&OPT_LIQUIDITY_BUFFER_FACTOR)? {
+                Some(factor) => parse_buffer_factor(&factor)?,
+                None => DEFAULT_LIQUIDITY_BUFFER_FACTOR,
+            },
+            margin_msat: msat_option(
+                plugin.option(&OPT_LIQUIDITY_MARGIN_SAT)?,
+                OPT_LIQUIDITY_MARGIN_SAT.name,
+            )?
+            .unwrap_or(0),
+            min_fallback_msat: msat_option(
+                plugin.option(&OPT_FALLBACK_MIN_AMOUNT_SAT)?,
+                OPT_FALLBACK_MIN_AMOUNT_SAT.name,
+            )?,
+            max_fallback_msat: msat_option(
+                plugin.option(&OPT_FALLBACK_MAX_AMOUNT_SAT)?,
+                OPT_FALLBACK_MAX_AMOUNT_SAT.name,
+            )?,
+            opt_out_label_prefixes: plugin
+                .option(&OPT_FALLBACK_OPT_OUT_LABEL_PREFIX)?
+                .unwrap_or_default(),
+        };
+        let mint_poll_interval = Duration::from_secs(
+            int_option(
+                plugin.option(&OPT_MINT_POLL_INTERVAL_SECS)?,
+                OPT_MINT_POLL_INTERVAL_SECS.name,
+            )?
+            .unwrap_or(DEFAULT_MINT_POLL_INTERVAL_SECS),
+        );
+        let channel_check_interval = Duration::from_secs(
+            int_option(
+                plugin.option(&OPT_CHANNEL_CHECK_INTERVAL_SECS)?,
+                OPT_CHANNEL_CHECK_INTERVAL_SECS.name,
+            )?
+            .unwrap_or(DEFAULT_CHANNEL_CHECK_INTERVAL_SECS),
+        );
+        let channel_cooldown = Duration::from_secs(
+            int_option(
+                plugin.option(&OPT_CHANNEL_COOLDOWN_SECS)?,
+                OPT_CHANNEL_COOLDOWN_SECS.name,
+            )?
+            .unwrap_or(DEFAULT_CHANNEL_COOLDOWN_SECS),
+        );
+        let max_concurrent_orders = int_option(
+            plugin.option(&OPT_MAX_CONCURRENT_ORDERS)?,
+            OPT_MAX_CONCURRENT_ORDERS.name,
+        )?
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_ORDERS) as usize;
+        let max_channel_fee_sat = int_option(
+            plugin.option(&OPT_MAX_CHANNEL_FEE_SAT)?,
+            OPT_MAX_CHANNEL_FEE_SAT.name,
+        )?;
+        let max_channel_fee_percent = match plugin.option(&OPT_MAX_CHANNEL_FEE_PERCENT)? {
+            Some(percent) => parse_fee_percent(&percent)?,
+            None => DEFAULT_MAX_CHANNEL_FEE_PERCENT,
+        };
+
+        let fallback_mode = match plugin.option(&OPT_FALLBACK_MODE)? {
+            Some(mode) => Some(mode),
+            None => deprecated_env("FALLBACK_MODE", OPT_FALLBACK_MODE.name),
+        };
+        let fallback = FallbackPolicy {
+            mode: FallbackMode::from_str(fallback_mode.as_deref().unwrap_or("ecash"))?,
+            jit_min_amount_sat: int_option_or_env(
+                plugin.option(&OPT_JIT_MIN_AMOUNT_SAT)?,
+                "JIT_MIN_AMOUNT_SAT",
+                OPT_JIT_MIN_AMOUNT_SAT.name,
+            )?
+            .unwrap_or(DEFAULT_JIT_MIN_AMOUNT_SAT),
+        };
+        if fallback.mode != FallbackMode::Ecash && lsps2_node.is_none() {
+            return Err(anyhow!(
+                "JIT fallback needs {} or {}",
+                OPT_LSPS2_NODE.name,
+                OPT_LSP_NODE.name
+            ));
+        }
+
+        Ok(Config {
+            network,
+            mints,
+            lsp_nodes,
+            lsp_urls,
+            lsps2_node,
+            target_channel_size_sat,
+            liquidity_policy,
+            mint_poll_interval,
+            channel_check_interval,
+            channel_cooldown,
+            max_concurrent_orders,
+            max_channel_fee_sat,
+            max_channel_fee_percent,
+            fallback,
+            fallback_enabled: plugin.option(&OPT_ENABLE_FALLBACK)?.unwrap_or(true),
+            channel_manager_enabled,
+            lightning_dir: PathBuf::from(plugin.configuration().lightning_dir),
+        })
+    }
+}
+
+// handles setconfig calls for our dynamic options and applies them to the running tasks
+pub async fn setconfig_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let name = v["config"]
+        .as_str()
+        .ok_or(anyhow!("setconfig call without config name"))?;
+    // integers may arrive as numbers or strings
+    let value = match &v["val"] {
+        serde_json::Value::String(value) => value.clone(),
+        serde_json::Value::Number(value) => value.to_string(),
+        other => return Err(anyhow!("Invalid value for {}: {}", name, other)),
+    };
+    let mut config = p.state().config();
+    let option_value = if name == OPT_MINT_URL.name {
+        // the mints are switched before the config so no invoices are issued by unreachable mints
+        let default_max_balance_sat = p.option(&OPT_MINT_MAX_BALANCE_SAT)?.map(|max| max as u64);
+        let mints = parse_mints(&value, default_max_balance_sat)?;
+        p.state().wallet.lock().await.set_mints(&mints).await?;
+        config.mints = mints;
+        options::Value::String(value)
+    } else if name == OPT_TARGET_CHANNEL_SIZE_SAT.name {
+        let size = value
+            .parse::<u64>()
+            .map_err(|_| anyhow!("Invalid value for {}: {}", name, value))?;
+        config.target_channel_size_sat = size;
+        options::Value::Integer(size as i64)
+    } else if name == OPT_LIQUIDITY_BUFFER_FACTOR.name {
+        config.liquidity_policy.buffer_factor = parse_buffer_factor(&value)?;
+        options::Value::String(value)
+    } else {
+        return Err(anyhow!("{} can't be changed at runtime", name));
+    };
+    p.set_option_str(name, option_value)?;
+    *p.state().config.write().unwrap() = config;
+    info!("Changed {} at runtime", name);
+    Ok(json!({}))
+}
+
+// url[=max_sat],... with the default limit for mints without their own
+fn parse_mints(list: &str, default_max_balance_sat: Option<u64>) -> Result<Vec<MintConfig>> {
+    let mut mints = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let mint = match entry.split_once('=') {
+            Some((url, max)) => MintConfig {
+                url: url.trim().to_string(),
+                max_balance_sat: Some(
+                    max.trim()
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("Invalid balance limit for mint {}: {}", url, max))?,
+                ),
+            },
+            None => MintConfig {
+                url: entry.to_string(),
+                max_balance_sat: default_max_balance_sat,
+            },
+        };
+        if mints.iter().any(|other: &MintConfig| other.url == mint.url) {
+            return Err(anyhow!("Mint {} configured twice", mint.url));
+        }
+        mints.push(mint);
+    }
+    if mints.is_empty() {
+        return Err(anyhow!("{} needs at least one mint", OPT_MINT_URL.name));
+    }
+    Ok(mints)
+}
+
+fn parse_buffer_factor(factor: &str) -> Result<f64> {
+    let factor = factor.parse::<f64>()?;
+    if !(0.0..=1.0).contains(&factor) {
+        return Err(anyhow!(
+            "{} has to be between 0 and 1",
+            OPT_LIQUIDITY_BUFFER_FACTOR.name
+        ));
+    }
+    Ok(factor)
+}
+
+fn parse_fee_percent(percent: &str) -> Result<f64> {
+    match percent.parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(anyhow!(
+            "{} has to be between 0 and 100",
+            OPT_MAX_CHANNEL_FEE_PERCENT.name
+        )),
+    }
+}
+
+// integer option that can't be negative
+fn int_option(value: Option<i64>, option_name: &str) -> Result<Option<u64>> {
+    value
+        .map(|value| {
+            u64::try_from(value)
+                .map_err(|_| anyhow!("Invalid value for {}: {}", option_name, value))
+        })
+        .transpose()
+}
+
+// integer option with a deprecated env var as fallback
+fn int_option_or_env(value: Option<i64>, env_name: &str, option_name: &str) -> Result<Option<u64>> {
+    if value.is_some() {
+        return int_option(value, option_name);
+    }
+    deprecated_env(env_name, option_name)
+        .map(|value| {
+            value
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid value for {}: {}", option_name, value))
+        })
+        .transpose()
+}
+
+// sat option converted to msat, values that don't fit are refused
+fn msat_option(value: Option<i64>, option_name: &str) -> Result<Option<u64>> {
+    int_option(value, option_name)?
+        .map(|sat| {
+            sat.checked_mul(1000)
+                .ok_or(anyhow!("Invalid value for {}: {} sat", option_name, sat))
+        })
+        .transpose()
+}
+
+// reads an env var still supported for compatibility with older setups
+fn deprecated_env(env_name: &str, option_name: &str) -> Option<String> {
+    let value = env::var(env_name).ok().filter(|value| !value.is_empty())?;
+    warn!(
+        "Env var {} is deprecated, use the plugin option {} instead",
+        env_name, option_name
+    );
+    Some(value)
+}
+
+// comma separated list from a deprecated env var
+fn deprecated_env_list(env_name: &str, option_name: &str) -> Option<Vec<String>> {
+    let list: Vec<String> = deprecated_env(env_name, option_name)?
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    (!list.is_empty()).then_some(list)
+}
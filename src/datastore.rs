@@ -0,0 +1,135 @@
+use super::*;
+
+// JSON records in the CLN datastore under kickstart/<namespace>/<key>, so state
+// survives restarts of lightningd independent of the plugin's working directory
+
+const DATASTORE_PREFIX: &str = "kickstart";
+// namespace of the counters kept with `datastore_increment`
+pub const COUNTERS_NAMESPACE: &str = "counters";
+const INCREMENT_RETRIES: usize = 5;
+
+pub async fn datastore_put<T: Serialize>(namespace: &str, key: &str, value: &T) -> Result<()> {
+    let request = DatastoreRequest {
+        key: vec![
+            DATASTORE_PREFIX.to_string(),
+            namespace.to_string(),
+            key.to_string(),
+        ],
+        string: Some(serde_json::to_string(value)?),
+        hex: None,
+        mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+        generation: None,
+    };
+    match send_rpc_request(request.into()).await? {
+        Response::Datastore(_) => Ok(()),
+        _ => Err(anyhow!("Unexpected response")),
+    }
+}
+
+pub async fn datastore_get<T: DeserializeOwned>(namespace: &str, key: &str) -> Result<Option<T>> {
+    let full_key = vec![
+        DATASTORE_PREFIX.to_string(),
+        namespace.to_string(),
+        key.to_string(),
+    ];
+    let request = ListdatastoreRequest {
+        key: Some(full_key.clone()),
+    };
+    let entry = match send_rpc_request(request.into()).await? {
+        Response::ListDatastore(response) => response
+            .datastore
+            .into_iter()
+            .find(|entry| entry.key == full_key),
+        _ => return Err(anyhow!("Unexpected response")),
+    };
+    match entry.and_then(|entry| entry.string) {
+        Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+        None => Ok(None),
+    }
+}
+
+// all records of the namespace, unparseable ones are skipped
+pub async fn datastore_list<T: DeserializeOwned>(namespace: &str) -> Result<Vec<T>> {
+    let request = ListdatastoreRequest {
+        key: Some(vec![DATASTORE_PREFIX.to_string(), namespace.to_string()]),
+    };
+    let entries = match send_rpc_request(request.into()).await? {
+        Response::ListDatastore(response) => response.datastore,
+        _ => return Err(anyhow!("Unexpected response")),
+    };
+    Ok(entries
+        .into_iter()
+        .filter_map(
+            |entry| match serde_json::from_str(entry.string.as_deref()?) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    warn!("Skipping invalid datastore entry {:?}: {}", entry.key, e);
+                    None
+                }
+            },
+        )
+        .collect())
+}
+
+pub async fn datastore_delete(namespace: &str, key: &str) -> Result<()> {
+    let request = DeldatastoreRequest {
+        key: vec![
+            DATASTORE_PREFIX.to_string(),
+            namespace.to_string(),
+            key.to_string(),
+        ],
+        generation: None,
+    };
+    match send_rpc_request(request.into()).await? {
+        Response::DelDatastore(_) => Ok(()),
+        _ => Err(anyhow!("Unexpected response")),
+    }
+}
+
+// increments the counter stored under the key and returns the new value, concurrent
+// increments are detected by the datastore generation and retried
+pub async fn datastore_increment(namespace: &str, key: &str) -> Result<u64> {
+    let full_key = vec![
+        DATASTORE_PREFIX.to_string(),
+        namespace.to_string(),
+        key.to_string(),
+    ];
+    for _ in 0..INCREMENT_RETRIES {
+        let request = ListdatastoreRequest {
+            key: Some(full_key.clone()),
+        };
+        let current = match send_rpc_request(request.into()).await? {
+            Response::ListDatastore(response) => response
+                .datastore
+                .into_iter()
+                .find(|entry| entry.key == full_key),
+            _ => return Err(anyhow!("Unexpected response")),
+        };
+        let (next, mode, generation) = match current {
+            Some(entry) => (
+                entry
+                    .string
+                    .as_deref()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or(anyhow!("Invalid counter {:?}", entry.key))?
+                    + 1,
+                DatastoreMode::MUST_REPLACE,
+                entry.generation,
+            ),
+            None => (1, DatastoreMode::MUST_CREATE, None),
+        };
+        let request = DatastoreRequest {
+            key: full_key.clone(),
+            string: Some(next.to_string()),
+            hex: None,
+            mode: Some(mode),
+            generation,
+        };
+        match send_rpc_request(request.into()).await {
+            Ok(Response::Datastore(_)) => return Ok(next),
+            Ok(_) => return Err(anyhow!("Unexpected response")),
+            Err(e) => debug!("Counter {:?} changed concurrently: {}", full_key, e),
+        }
+    }
+    Err(anyhow!("Could not increment counter {:?}", full_key))
+}
@@ -1,167 +1,879 @@
 use super::*;
 
+const SEED_FILE_NAME: &str = "kickstart-cashu-seed";
+const WALLET_DB_FILE_NAME: &str = "cashu_wallet.db";
+// datastore namespace of the mint quotes we handed out invoices for
+const MINT_QUOTES_NAMESPACE: &str = "mint_quotes";
+// polling backs off up to this multiple of the configured interval
+const MAX_POLL_BACKOFF_FACTOR: u32 = 8;
+// quotes pushed over NUT-17 are still polled this often in case a notification got lost
+const SAFETY_POLL_INTERVAL: Duration = Duration::from_secs(300);
+// an unreachable mint isn't asked for invoices again for this long
+const MINT_OFFLINE_BACKOFF_SECS: u64 = 120;
+// moving the funds of a retired mint isn't tried again for this long after a failure
+const MIGRATION_BACKOFF_SECS: u64 = 600;
+
+// a configured mint, with the most we trust it to hold
+struct MintWallet {
+    wallet: Wallet,
+    max_balance_sat: Option<u64>,
+    // unix time until which the mint is considered offline
+    offline_until: u64,
+    // set once the mint issued an invoice for another network than our node's
+    wrong_network: bool,
+}
+
 pub struct EcashWallet {
-    cdk_wallet: Wallet,
+    // configured mints in order of preference
+    mint_wallets: Vec<MintWallet>,
+    // wallets of mints no longer configured, kept until their funds are moved to the configured ones
+    retired_wallets: Vec<Wallet>,
+    // retired mint url -> unix time of the next attempt to move its funds
+    migration_retry_at: HashMap<String, u64>,
     pending_mint_requests: Vec<PaymentRequest>,
+    seed: [u8; 32],
+    database: Arc<WalletRedbDatabase>,
+    quote_watcher: Arc<MintQuoteWatcher>,
+    network: Currency,
     pub last_balance: u64,
+    // notified whenever last_balance changes
+    pub balance_changed: Arc<Notify>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaymentRequest {
     pub bolt11: String,
     pub mint_quote_id: String,
+    pub mint_url: String,
+    // counts towards the mint's exposure limit while pending
+    #[serde(default)]
+    pub amount_sat: u64,
     pub expiry: u64,
+    // what the mint was asked to put into the invoice, None if it doesn't support descriptions
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 impl EcashWallet {
-    pub async fn new() -> Result<Self> {
-        let (seed, newly_generated) = gen_or_read_seed()?;
-        let mint_url = match env::var("MINT_URL") {
-            Ok(url) if url.len() > 1 => url,
-            _ => {
-                warn!("MINT_URL not set, using default (mint.coinos.io)");
-                "https://mint.coinos.io".to_string()
-            }
-        };
+    pub async fn new(config: &Config) -> Result<Self> {
+        let (seed, newly_generated) = gen_or_read_seed(&config.lightning_dir)?;
         // let database = cdk_sqlite::WalletSqliteDatabase::new(Path::new("cashu_wallet.db")).await?;
         // database.migrate().await;
-        let database = cdk_redb::WalletRedbDatabase::new(Path::new("cashu_wallet.db"))?;
-        let cdk_wallet = Wallet::new(
-            &mint_url,
-            CurrencyUnit::Sat,
-            Arc::new(database),
-            &seed,
-            None,
-        )?;
-        let existing_balance = cdk_wallet.total_balance().await?;
-        if existing_balance == Amount::from(0) && !newly_generated {
-            warn!("Found no balance in database on already existing secret, scanning for existing proofs...");
-            let restored_amount: Amount = cdk_wallet.restore().await?;
-            warn!("Restored balance: {}", restored_amount);
-        }
-        let balance = cdk_wallet.total_balance().await?;
-        Ok(Self {
-            cdk_wallet,
+        let database = Arc::new(WalletRedbDatabase::new(&wallet_db_path(
+            &config.lightning_dir,
+        )?)?);
+        let mut mint_wallets = Vec::new();
+        for mint in &config.mints {
+            let cdk_wallet =
+                Wallet::new(&mint.url, CurrencyUnit::Sat, database.clone(), &seed, None)?;
+            let existing_balance = cdk_wallet.total_balance().await?;
+            if existing_balance == Amount::from(0) && !newly_generated {
+                warn!("Found no balance for {} on already existing secret, scanning for existing proofs...", mint.url);
+                // an offline mint must not keep the plugin from starting, the others can take over
+                match cdk_wallet.restore().await {
+                    Ok(restored_amount) => warn!("Restored balance: {}", restored_amount),
+                    Err(e) => warn!("Restoring proofs of {} failed: {}", mint.url, e),
+                }
+            }
+            mint_wallets.push(MintWallet {
+                wallet: cdk_wallet,
+                max_balance_sat: mint.max_balance_sat,
+                offline_until: 0,
+                wrong_network: false,
+            });
+        }
+        let mut ecash_wallet = Self {
+            mint_wallets,
+            retired_wallets: Vec::new(),
+            migration_retry_at: HashMap::new(),
             pending_mint_requests: Vec::new(),
-            last_balance: balance.into(),
-        })
+            seed,
+            database,
+            quote_watcher: Arc::new(MintQuoteWatcher::new()),
+            network: config.network.clone(),
+            last_balance: 0,
+            balance_changed: Arc::new(Notify::new()),
+        };
+        ecash_wallet.last_balance = ecash_wallet.get_total_balance().await?;
+        Ok(ecash_wallet)
+    }
+
+    // configured and retired mints
+    fn wallets(&self) -> impl Iterator<Item = &Wallet> {
+        self.mint_wallets
+            .iter()
+            .map(|mint_wallet| &mint_wallet.wallet)
+            .chain(self.retired_wallets.iter())
     }
 
+    // includes funds still held on mints we switched away from
     pub async fn get_total_balance(&self) -> Result<u64> {
-        Ok(self.cdk_wallet.total_balance().await?.into())
+        let mut balance = 0;
+        for wallet in self.wallets() {
+            balance += u64::from(wallet.total_balance().await?);
+        }
+        Ok(balance)
     }
 
-    pub async fn pay_lightning_invoice(&self, bolt11_invoice: String) -> Result<String> {
-        // get melt quote for invoice
-        let melt_quote = self.cdk_wallet.melt_quote(bolt11_invoice, None).await?;
-        // pay invoice
-        let melted = self.cdk_wallet.melt(&melt_quote.id).await?; // blocking till paid
-        if melted.state != MeltQuoteState::Paid {
-            return Err(anyhow!("Invoice not paid, Status: {:?}", melted.state));
+    pub fn network(&self) -> &Currency {
+        &self.network
+    }
+
+    // updates last_balance, waking up whoever waits for balance changes
+    pub async fn refresh_balance(&mut self) -> Result<u64> {
+        let balance = self.get_total_balance().await?;
+        if balance != self.last_balance {
+            self.last_balance = balance;
+            self.balance_changed.notify_one();
         }
-        Ok(melted.preimage.unwrap_or(String::new()))
+        Ok(balance)
     }
 
-    pub async fn create_lightning_invoice(&mut self, amount_sat: u64) -> Result<PaymentRequest> {
-        let mint_quote = self
-            .cdk_wallet
-            .mint_quote(Amount::from(amount_sat), None)
-            .await?;
-        debug!("Mint quote: {:?}", mint_quote);
-        let paymet_request = PaymentRequest {
-            bolt11: mint_quote.request.clone(),
-            mint_quote_id: mint_quote.id.clone(),
-            expiry: mint_quote.expiry,
+    // balance per mint, retired mints included
+    pub async fn get_balances(&self) -> Result<Vec<(String, u64)>> {
+        let mut balances = Vec::new();
+        for wallet in self.wallets() {
+            balances.push((
+                wallet.mint_url.to_string(),
+                wallet.total_balance().await?.into(),
+            ));
+        }
+        Ok(balances)
+    }
+
+    // payments have to come from a single mint, so we spend from the one holding the most
+    async fn richest_wallet(&self) -> Result<(&Wallet, u64)> {
+        let mut richest = None;
+        for wallet in self.wallets() {
+            let balance: u64 = wallet.total_balance().await?.into();
+            if richest.map_or(true, |(_, richest_balance)| balance > richest_balance) {
+                richest = Some((wallet, balance));
+            }
+        }
+        richest.ok_or(anyhow!("No mint configured"))
+    }
+
+    // what the mint holds or will hold once its pending quotes are paid
+    async fn exposure_sat(&self, wallet: &Wallet) -> Result<u64> {
+        let mint_url = wallet.mint_url.to_string();
+        let pending_sat: u64 = self
+            .pending_mint_requests
+            .iter()
+            .filter(|request| request.mint_url == mint_url)
+            .map(|request| request.amount_sat)
+            .sum();
+        Ok(u64::from(wallet.total_balance().await?) + pending_sat)
+    }
+
+    pub fn pending_mint_requests(&self) -> &[PaymentRequest] {
+        &self.pending_mint_requests
+    }
+
+    // reloads the quotes persisted before a restart, they are re-checked by
+    // `mint_pending_mint_requests`, also if they expired while we were down
+    pub async fn load_pending_mint_requests(&mut self) -> Result<()> {
+        let requests: Vec<PaymentRequest> = datastore_list(MINT_QUOTES_NAMESPACE).await?;
+        for request in requests {
+            if self
+                .pending_mint_requests
+                .iter()
+                .any(|pending| pending.mint_quote_id == request.mint_quote_id)
+            {
+                continue;
+            }
+            // the mint may have been changed while we were down
+            if self.wallet_for_mint(&request.mint_url).is_none() {
+                self.retired_wallets.push(Wallet::new(
+                    &request.mint_url,
+                    CurrencyUnit::Sat,
+                    self.database.clone(),
+                    &self.seed,
+                    None,
+                )?);
+            }
+            info!("Reloaded pending mint quote {}", request.mint_quote_id);
+            self.quote_watcher
+                .watch(&request.mint_url, &request.mint_quote_id, request.expiry);
+            self.pending_mint_requests.push(request);
+        }
+        Ok(())
+    }
+
+    // pending quotes with the wallet of their mint, to check them without holding the lock
+    fn pending_quotes_with_wallets(&self) -> Vec<(PaymentRequest, Wallet)> {
+        self.pending_mint_requests
+            .iter()
+            .filter_map(|request| {
+                let wallet = self.wallet_for_mint(&request.mint_url)?.clone();
+                Some((request.clone(), wallet))
+            })
+            .collect()
+    }
+
+    async fn remove_pending_mint_request(&mut self, mint_quote_id: &str) {
+        self.pending_mint_requests
+            .retain(|request| request.mint_quote_id != mint_quote_id);
+        if let Err(e) = datastore_delete(MINT_QUOTES_NAMESPACE, mint_quote_id).await {
+            warn!(
+                "Error deleting mint quote {} from datastore: {}",
+                mint_quote_id, e
+            );
+        }
+    }
+
+    // applies a changed mint list, wallets of mints no longer configured are retired and their
+    // funds moved over by `migrate_retired_balances` once their pending quotes are settled
+    pub async fn set_mints(&mut self, mints: &[MintConfig]) -> Result<()> {
+        let mut old_wallets: Vec<Wallet> = std::mem::take(&mut self.mint_wallets)
+            .into_iter()
+            .map(|mint_wallet| mint_wallet.wallet)
+            .chain(std::mem::take(&mut self.retired_wallets))
+            .collect();
+        let mut mint_wallets = Vec::new();
+        for mint in mints {
+            let wallet = match old_wallets
+                .iter()
+                .position(|wallet| wallet.mint_url.to_string() == mint.url.trim_end_matches('/'))
+            {
+                Some(index) => old_wallets.remove(index),
+                None => Wallet::new(
+                    &mint.url,
+                    CurrencyUnit::Sat,
+                    self.database.clone(),
+                    &self.seed,
+                    None,
+                )?,
+            };
+            mint_wallets.push(MintWallet {
+                wallet,
+                max_balance_sat: mint.max_balance_sat,
+                offline_until: 0,
+                wrong_network: false,
+            });
+        }
+        // refuse a mint list without any reachable mint
+        let mut reachable = false;
+        for mint_wallet in &mint_wallets {
+            reachable |= mint_wallet.wallet.get_mint_info().await.is_ok();
+        }
+        // put everything back on failure, nothing may get lost
+        if !reachable {
+            self.retired_wallets = old_wallets;
+            for mint_wallet in mint_wallets.into_iter().rev() {
+                self.retired_wallets.insert(0, mint_wallet.wallet);
+            }
+            return Err(anyhow!("None of the new mints is reachable"));
+        }
+        for wallet in &old_wallets {
+            info!("Retiring mint {}", wallet.mint_url);
+        }
+        self.mint_wallets = mint_wallets;
+        self.retired_wallets = old_wallets;
+        Ok(())
+    }
+
+    // forgets retired mints without balance or pending quotes, returns the ones whose balance
+    // can be moved now, only the local wallet database is read
+    async fn take_stock_of_retired_wallets(&mut self) -> Result<Vec<(Wallet, u64)>> {
+        let mut remaining_wallets = Vec::new();
+        let mut movable = Vec::new();
+        let now = unix_time();
+        for old_wallet in std::mem::take(&mut self.retired_wallets) {
+            let old_mint_url = old_wallet.mint_url.to_string();
+            // backing off after a failed attempt
+            if self
+                .migration_retry_at
+                .get(&old_mint_url)
+                .is_some_and(|retry_at| *retry_at > now)
+            {
+                remaining_wallets.push(old_wallet);
+                continue;
+            }
+            let balance: u64 = match old_wallet.total_balance().await {
+                Ok(balance) => balance.into(),
+                Err(e) => {
+                    warn!(
+                        "Error reading balance of retired mint {}: {}",
+                        old_mint_url, e
+                    );
+                    remaining_wallets.push(old_wallet);
+                    continue;
+                }
+            };
+            let has_pending_quotes = self
+                .pending_mint_requests
+                .iter()
+                .any(|request| request.mint_url == old_mint_url);
+            if balance == 0 && !has_pending_quotes {
+                info!("All funds moved away from retired mint {}", old_mint_url);
+                self.migration_retry_at.remove(&old_mint_url);
+                continue;
+            }
+            if balance > 0 && !has_pending_quotes {
+                movable.push((old_wallet.clone(), balance));
+            }
+            remaining_wallets.push(old_wallet);
+        }
+        self.retired_wallets = remaining_wallets;
+        Ok(movable)
+    }
+
+    // takes proofs out of the wallet as a token, optionally locked to a P2PK public key
+    pub async fn send_token(&self, amount_sat: u64, p2pk_pubkey: Option<&str>) -> Result<Token> {
+        let conditions = match p2pk_pubkey {
+            Some(pubkey) => Some(SpendingConditions::new_p2pk(
+                CashuPublicKey::from_str(pubkey)?,
+                None,
+            )),
+            None => None,
         };
+        let (wallet, _) = self.richest_wallet().await?;
+        Ok(wallet
+            .send(
+                Amount::from(amount_sat),
+                None,
+                conditions,
+                &SplitTarget::None,
+                &SendKind::default(),
+                false,
+            )
+            .await?)
+    }
+
+    // swaps the proofs of the token into our wallet, tokens of other mints are kept in a
+    // retired wallet for that mint and moved over to the configured mints later, tokens that
+    // would take a configured mint over its limit are refused
+    pub async fn receive_token(&mut self, encoded_token: &str) -> Result<(String, u64)> {
+        let token = Token::from_str(encoded_token)?;
+        let mint_url = token.mint_url()?.to_string();
+        if let Some(mint_wallet) = self
+            .mint_wallets
+            .iter()
+            .find(|mint_wallet| mint_wallet.wallet.mint_url.to_string() == mint_url)
+        {
+            if let Some(max_balance_sat) = mint_wallet.max_balance_sat {
+                let token_sat: u64 = token.value()?.into();
+                let exposure_sat = self.exposure_sat(&mint_wallet.wallet).await?;
+                if exposure_sat.saturating_add(token_sat) > max_balance_sat {
+                    return Err(anyhow!(
+                        "Token of {} sat would take {} over its limit of {} sat",
+                        token_sat,
+                        mint_url,
+                        max_balance_sat
+                    ));
+                }
+            }
+        }
+        if self.wallet_for_mint(&mint_url).is_none() {
+            info!(
+                "Received token of unknown mint {}, will move the funds to the configured mints",
+                mint_url
+            );
+            self.retired_wallets.push(Wallet::new(
+                &mint_url,
+                CurrencyUnit::Sat,
+                self.database.clone(),
+                &self.seed,
+                None,
+            )?);
+        }
+        let wallet = self
+            .wallet_for_mint(&mint_url)
+            .ok_or(anyhow!("No wallet for mint {}", mint_url))?;
+        let amount = wallet
+            .receive(encoded_token, SplitTarget::None, &[], &[])
+            .await?;
+        Ok((mint_url, amount.into()))
+    }
+
+    fn wallet_for_mint(&self, mint_url: &str) -> Option<&Wallet> {
+        self.wallets()
+            .find(|wallet| wallet.mint_url.to_string() == mint_url)
+    }
+
+    // the wallet to pay from with its balance, cloned so the melt can run without holding
+    // the wallet lock, the melt of a hold invoice only returns once it settles
+    pub async fn payment_wallet(&self) -> Result<(Wallet, u64)> {
+        let (wallet, balance) = self
+            .richest_wallet()
+            .await
+            .map_err(|e| InvoiceNotPaid(e.to_string()))?;
+        Ok((wallet.clone(), balance))
+    }
+
+    // issues the invoice from the first configured mint that is online, stays below its
+    // exposure limit with this amount and accepts the quote
+    pub async fn create_lightning_invoice(
+        &mut self,
+        amount_sat: u64,
+        description: Option<&str>,
+    ) -> Result<PaymentRequest> {
+        let payment_request = self.request_mint_quote(amount_sat, description).await?;
+        // the mint loop picks up the new quote right away
+        self.quote_watcher.changed.notify_one();
+        Ok(payment_request)
+    }
+
+    // creates and tracks a mint quote without waking the mint loop, used directly for
+    // moving funds from retired mints as that runs in the mint loop itself
+    async fn request_mint_quote(
+        &mut self,
+        amount_sat: u64,
+        description: Option<&str>,
+    ) -> Result<PaymentRequest> {
+        let mut rejections = Vec::new();
+        for index in 0..self.mint_wallets.len() {
+            let mint_wallet = &self.mint_wallets[index];
+            let mint_url = mint_wallet.wallet.mint_url.to_string();
+            if mint_wallet.offline_until > unix_time() {
+                rejections.push(format!("{} offline", mint_url));
+                continue;
+            }
+            if mint_wallet.wrong_network {
+                rejections.push(format!("{} on another network", mint_url));
+                continue;
+            }
+            let exposure_sat = self.exposure_sat(&mint_wallet.wallet).await?;
+            if let Some(max_balance_sat) = mint_wallet.max_balance_sat {
+                if exposure_sat + amount_sat > max_balance_sat {
+                    rejections.push(format!(
+                        "{} would exceed its limit of {} sat",
+                        mint_url, max_balance_sat
+                    ));
+                    continue;
+                }
+            }
+            let mut description = description.map(str::to_string);
+            let mint_quote = match mint_wallet
+                .wallet
+                .mint_quote(Amount::from(amount_sat), description.clone())
+                .await
+            {
+                // mints without description support (NUT-04) get the invoice without
+                Err(error::Error::InvoiceDescriptionUnsupported) => {
+                    description = None;
+                    mint_wallet
+                        .wallet
+                        .mint_quote(Amount::from(amount_sat), None)
+                        .await
+                }
+                result => result,
+            };
+            match mint_quote {
+                Ok(mint_quote) => {
+                    debug!("Mint quote: {:?}", mint_quote);
+                    // a mint for another network would let payers pay with the wrong coins
+                    let currency = Bolt11Invoice::from_str(&mint_quote.request)
+                        .map(|invoice| invoice.currency());
+                    if currency.as_ref() != Ok(&self.network) {
+                        error!(
+                            "Mint {} issued an invoice for {:?}, our node is on {:?}, not using it anymore",
+                            mint_url, currency, self.network
+                        );
+                        self.mint_wallets[index].wrong_network = true;
+                        rejections.push(format!("{} on another network", mint_url));
+                        continue;
+                    }
+                    let paymet_request = PaymentRequest {
+                        bolt11: mint_quote.request.clone(),
+                        mint_quote_id: mint_quote.id.clone(),
+                        mint_url,
+                        amount_sat,
+                        expiry: mint_quote.expiry,
+                        description,
+                    };
+                    return self.track_mint_request(paymet_request).await;
+                }
+                Err(e) => {
+                    // an unreachable mint is skipped for a while, a mint rejecting the
+                    // amount may still take smaller invoices
+                    if mint_wallet.wallet.get_mint_info().await.is_err() {
+                        warn!("Mint {} is offline, failing over: {}", mint_url, e);
+                        self.mint_wallets[index].offline_until =
+                            unix_time() + MINT_OFFLINE_BACKOFF_SECS;
+                    }
+                    rejections.push(format!("{} failed: {}", mint_url, e));
+                }
+            }
+        }
+        Err(anyhow!(
+            "No mint could issue the invoice ({})",
+            rejections.join(", ")
+        ))
+    }
+
+    async fn track_mint_request(
+        &mut self,
+        paymet_request: PaymentRequest,
+    ) -> Result<PaymentRequest> {
+        // persisted before the invoice is handed out so a restart can't lose the payment
+        datastore_put(
+            MINT_QUOTES_NAMESPACE,
+            &paymet_request.mint_quote_id,
+            &paymet_request,
+        )
+        .await?;
+        self.quote_watcher.watch(
+            &paymet_request.mint_url,
+            &paymet_request.mint_quote_id,
+            paymet_request.expiry,
+        );
         self.pending_mint_requests.push(paymet_request.clone());
         Ok(paymet_request)
     }
 
-    pub async fn check_invoice_status(&self, mint_quote_id: &str) -> Result<bool> {
-        let status = self
-            .cdk_wallet
-            .mint_quote_state(mint_quote_id)
-            .await?
-            .paid
-            .unwrap_or(false);
-        if status {
-            self.cdk_wallet
-                .mint(mint_quote_id, SplitTarget::None, None)
-                .await?;
+    pub async fn check_invoice_status(&self, request: &PaymentRequest) -> Result<bool> {
+        // quotes may belong to a mint we switched away from in the meantime
+        let wallet = self
+            .wallet_for_mint(&request.mint_url)
+            .ok_or(anyhow!("No wallet for mint {}", request.mint_url))?;
+        let quote_state = wallet.mint_quote_state(&request.mint_quote_id).await?;
+        match quote_state.state {
+            MintQuoteState::Paid => {
+                wallet
+                    .mint(&request.mint_quote_id, SplitTarget::None, None)
+                    .await?;
+                Ok(true)
+            }
+            // already minted before a restart
+            MintQuoteState::Issued => Ok(true),
+            _ => Ok(false),
         }
-        Ok(status)
     }
 }
 
-pub async fn mint_pending_mint_requests(wallet: Arc<Mutex<EcashWallet>>) -> Result<()> {
+// mints paid quotes as soon as the mint pushes the payment over NUT-17, quotes of
+// mints without NUT-17 are polled with increasing delay while nothing gets paid
+pub async fn mint_pending_mint_requests(
+    plugin: Plugin<PluginState>,
+    poll_interval: Duration,
+) -> Result<()> {
+    let wallet = Arc::clone(&plugin.state().wallet);
+    let invoices = Arc::clone(&plugin.state().invoices);
+    let quote_watcher = Arc::clone(&wallet.lock().await.quote_watcher);
+    let max_poll_interval = poll_interval * MAX_POLL_BACKOFF_FACTOR;
+    let mut delay = poll_interval;
     loop {
-        if !wallet.lock().await.pending_mint_requests.is_empty() {
+        let pending = wallet.lock().await.pending_quotes_with_wallets();
+        let mut any_paid = false;
+        if !pending.is_empty() {
             trace!("Checking pending mint requests...");
+            // quote states are fetched without holding the wallet lock
+            for (quote, mint_wallet) in &pending {
+                let quote_state = match mint_wallet.mint_quote_state(&quote.mint_quote_id).await {
+                    Ok(quote_state) => quote_state.state,
+                    Err(e) => {
+                        warn!("Error checking quote {}: {}", quote.mint_quote_id, e);
+                        continue;
+                    }
+                };
+                let mut wallet = wallet.lock().await;
+                match quote_state {
+                    MintQuoteState::Paid => {
+                        match mint_wallet
+                            .mint(&quote.mint_quote_id, SplitTarget::None, None)
+                            .await
+                        {
+                            Ok(amount) => {
+                                debug!("Quote paid: {} ({} sat)", quote.mint_quote_id, amount);
+                                any_paid = true;
+                                if let Some(record) = invoices
+                                    .mark_quote_paid(&quote.mint_quote_id, u64::from(amount) * 1000)
+                                    .await
+                                {
+                                    notify_invoice_paid(&plugin, &record).await;
+                                }
+                                wallet
+                                    .remove_pending_mint_request(&quote.mint_quote_id)
+                                    .await;
+                            }
+                            Err(e) => warn!("Error minting quote {}: {}", quote.mint_quote_id, e),
+                        }
+                    }
+                    // already minted before a restart
+                    MintQuoteState::Issued => {
+                        if let Some(record) = invoices
+                            .mark_quote_paid(&quote.mint_quote_id, quote.amount_sat * 1000)
+                            .await
+                        {
+                            notify_invoice_paid(&plugin, &record).await;
+                        }
+                        wallet
+                            .remove_pending_mint_request(&quote.mint_quote_id)
+                            .await;
+                    }
+                    // expired quotes are checked a last time, they may have been paid while we were down
+                    _ if quote.expiry < unix_time() => {
+                        debug!("Quote expired: {}", quote.mint_quote_id);
+                        wallet
+                            .remove_pending_mint_request(&quote.mint_quote_id)
+                            .await;
+                    }
+                    _ => {}
+                }
+            }
             let mut wallet = wallet.lock().await;
+            wallet.refresh_balance().await?;
+        }
+        if !wallet.lock().await.retired_wallets.is_empty() {
+            // a retired mint that can't be handled now must not stop the minting
+            if let Err(e) = migrate_retired_balances(&wallet).await {
+                warn!("Error moving funds from retired mints: {}", e);
+            }
+            wallet.lock().await.refresh_balance().await?;
+        }
 
-            wallet.pending_mint_requests.retain(|quote| {
-                if quote.expiry < unix_time() {
-                    debug!("Quote expired: {}", quote.mint_quote_id);
-                    false
-                } else {
-                    true
-                }
-            });
-            let mut status_vec: Vec<bool> = Vec::new();
-            for quote in &wallet.pending_mint_requests {
-                status_vec.push(wallet.check_invoice_status(&quote.mint_quote_id).await?);
-            }
-            // remove paid invoices
-            wallet.pending_mint_requests.retain(|quote| {
-                if status_vec.pop().unwrap() {
-                    debug!("Quote paid: {}", quote.mint_quote_id);
-                    false
-                } else {
-                    true
-                }
-            });
-            wallet.last_balance = wallet.get_total_balance().await?;
+        delay = if any_paid {
+            poll_interval
+        } else {
+            (delay * 2).min(max_poll_interval)
+        };
+        // with all quotes pushed by their mints polling is only a safety net
+        let sleep = if quote_watcher
+            .all_subscribed(pending.iter().map(|(quote, _)| quote.mint_url.as_str()))
+        {
+            SAFETY_POLL_INTERVAL.max(delay)
+        } else {
+            delay
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => {}
+            // a quote was paid or a new one handed out
+            _ = quote_watcher.changed.notified() => delay = poll_interval,
+        }
+    }
+}
+
+// moves the balance of retired mints to the configured ones by paying a mint quote of a
+// configured mint with a melt on the old one, once no quotes are pending on the old mint,
+// the wallet is only locked to create the invoice, not during the melt
+async fn migrate_retired_balances(ecash_wallet: &Mutex<EcashWallet>) -> Result<()> {
+    let movable = ecash_wallet
+        .lock()
+        .await
+        .take_stock_of_retired_wallets()
+        .await?;
+    for (old_wallet, balance) in movable {
+        // keep some sats for the lightning fee of the old mint, dust stays where it is
+        let fee_reserve = (balance / 50).max(4);
+        if balance <= fee_reserve {
+            debug!(
+                "Leaving {} sat on retired mint {}, too little to cover the lightning fee",
+                balance, old_wallet.mint_url
+            );
+            continue;
+        }
+        if let Err(e) = migrate_balance(ecash_wallet, &old_wallet, balance, fee_reserve).await {
+            warn!(
+                "Moving {} sat from retired mint {} failed, retrying in {} s: {}",
+                balance, old_wallet.mint_url, MIGRATION_BACKOFF_SECS, e
+            );
+            ecash_wallet.lock().await.migration_retry_at.insert(
+                old_wallet.mint_url.to_string(),
+                unix_time() + MIGRATION_BACKOFF_SECS,
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn migrate_balance(
+    ecash_wallet: &Mutex<EcashWallet>,
+    old_wallet: &Wallet,
+    balance: u64,
+    fee_reserve: u64,
+) -> Result<()> {
+    // an offline old mint must not leave quotes on the new mint behind
+    old_wallet.get_mint_info().await?;
+    let payment_request = ecash_wallet
+        .lock()
+        .await
+        .request_mint_quote(balance - fee_reserve, None)
+        .await?;
+    let melt_quote = async {
+        let melt_quote = old_wallet
+            .melt_quote(payment_request.bolt11.clone(), None)
+            .await?;
+        if u64::from(melt_quote.amount + melt_quote.fee_reserve) > balance {
+            return Err(anyhow!(
+                "Old mint asks for a fee reserve of {} sat",
+                melt_quote.fee_reserve
+            ));
+        }
+        Ok(melt_quote)
+    }
+    .await;
+    let not_paid = match melt_quote {
+        Ok(melt_quote) => match old_wallet.melt(&melt_quote.id).await {
+            Ok(melted) if melted.state == MeltQuoteState::Paid => None,
+            Ok(melted) if melted.state == MeltQuoteState::Unpaid => {
+                Some(anyhow!("Invoice not paid, Status: {:?}", melted.state))
+            }
+            // a payment that may still be in flight keeps its quote, so it is minted if it arrives
+            Ok(melted) => {
+                return Err(anyhow!("Invoice not paid yet, Status: {:?}", melted.state));
+            }
+            Err(e) => return Err(e.into()),
+        },
+        Err(e) => Some(e),
+    };
+    // the quote won't be paid, it must not count towards the new mint's exposure
+    if let Some(e) = not_paid {
+        ecash_wallet
+            .lock()
+            .await
+            .remove_pending_mint_request(&payment_request.mint_quote_id)
+            .await;
+        return Err(e);
+    }
+    info!(
+        "Moved {} sat from retired mint {} to {}",
+        balance - fee_reserve,
+        old_wallet.mint_url,
+        payment_request.mint_url
+    );
+    Ok(())
+}
+
+// melts ecash to pay the invoice, refusing if the mint reserves more than max_fee_sat for fees
+// `balance` is what the wallet holds, taken from `EcashWallet::payment_wallet`
+pub async fn pay_lightning_invoice(
+    wallet: &Wallet,
+    balance: u64,
+    bolt11_invoice: String,
+    max_fee_sat: Option<u64>,
+) -> Result<MeltResult> {
+    // errors before the melt leave the invoice unpaid for sure
+    let not_paid = |e: Error| InvoiceNotPaid(e.to_string());
+    // get melt quote for invoice
+    let melt_quote = wallet
+        .melt_quote(bolt11_invoice, None)
+        .await
+        .map_err(|e| not_paid(e.into()))?;
+    let amount_sat: u64 = melt_quote.amount.into();
+    let fee_reserve_sat: u64 = melt_quote.fee_reserve.into();
+    info!(
+        "Melt quote {}: {} sat + up to {} sat fee reserve",
+        melt_quote.id, amount_sat, fee_reserve_sat
+    );
+    if let Some(max_fee_sat) = max_fee_sat {
+        if fee_reserve_sat > max_fee_sat {
+            return Err(InvoiceNotPaid(format!(
+                "Mint fee reserve of {} sat exceeds maxfee of {} sat",
+                fee_reserve_sat, max_fee_sat
+            ))
+            .into());
         }
-        tokio::time::sleep(Duration::from_secs(10)).await; // low timeout for demo
     }
+    if balance < amount_sat + fee_reserve_sat {
+        return Err(InvoiceNotPaid(format!(
+            "Insufficient balance: {} sat needed, {} sat available on a single mint",
+            amount_sat + fee_reserve_sat,
+            balance
+        ))
+        .into());
+    }
+    // pay invoice, an error here leaves it open whether the mint paid
+    let melted = wallet.melt(&melt_quote.id).await?; // blocking till paid
+    if melted.state == MeltQuoteState::Unpaid {
+        return Err(InvoiceNotPaid(format!("Invoice not paid, Status: {:?}", melted.state)).into());
+    }
+    if melted.state != MeltQuoteState::Paid {
+        return Err(anyhow!("Invoice not paid, Status: {:?}", melted.state));
+    }
+    Ok(MeltResult {
+        preimage: melted.preimage.unwrap_or(String::new()),
+        amount_sat,
+        fee_reserve_sat,
+        fee_paid_sat: melted.fee_paid.into(),
+    })
+}
+
+// the mint definitively didn't pay the invoice, as opposed to payments that may still be in flight
+#[derive(Debug)]
+pub struct InvoiceNotPaid(String);
+
+impl std::fmt::Display for InvoiceNotPaid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvoiceNotPaid {}
+
+#[derive(Debug, Serialize)]
+pub struct MeltResult {
+    pub preimage: String,
+    pub amount_sat: u64,
+    pub fee_reserve_sat: u64,
+    pub fee_paid_sat: u64,
 }
 
 /// Get the current unix time
-fn unix_time() -> u64 {
+pub fn unix_time() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .expect("Time went backwards")
         .as_secs()
 }
 
-/// load hex seed from env
-/// or generate a new one and save it in .env file
-fn gen_or_read_seed() -> Result<([u8; 32], bool)> {
+/// load hex seed from the seed file in the lightning dir (or the deprecated env var)
+/// or generate a new one and save it in the seed file
+fn gen_or_read_seed(lightning_dir: &Path) -> Result<([u8; 32], bool)> {
+    let seed_file = lightning_dir.join(SEED_FILE_NAME);
+    if let Ok(seed) = fs::read_to_string(&seed_file) {
+        trace!("Found existing seed file, loading...");
+        return Ok((parse_seed(seed.trim())?, false));
+    }
     match env::var("CASHU_SEED") {
         Ok(seed) if seed.len() == 64 => {
-            trace!("Found existing seed in env, loading...");
-            Ok((
-                hex::decode(seed)?
-                    .try_into()
-                    .map_err(|_| anyhow!("Invalid seed in env"))?,
-                false,
-            ))
+            warn!(
+                "Env var CASHU_SEED is deprecated, moving the seed to {}",
+                seed_file.display()
+            );
+            let seed = parse_seed(&seed)?;
+            write_seed_file(&seed_file, seed)?;
+            Ok((seed, false))
         }
         _ => {
-            warn!("No seed found in env, generating and saving new seed...");
+            warn!("No seed found, generating and saving new seed...");
             // generate new seed
             let seed = rand::thread_rng().gen::<[u8; 32]>();
-            // write newly generated seed into .env file (and create file if it doesn't exist)
-            write_seed_to_env_file(seed)?;
+            write_seed_file(&seed_file, seed)?;
             Ok((seed, true))
         }
     }
 }
 
-fn write_seed_to_env_file(seed: [u8; 32]) -> Result<()> {
-    info!("Writing new seed to .env file");
-    let seed = hex::encode(seed);
-    let mut file = OpenOptions::new().create(true).append(true).open(".env")?;
-    file.write_all(format!("CASHU_SEED={}\n", seed).as_bytes())?;
+/// the wallet db lives in the lightning dir, older versions kept it in the working directory
+/// of lightningd, such a db is copied over on the first start so its proofs aren't lost
+fn wallet_db_path(lightning_dir: &Path) -> Result<PathBuf> {
+    let db_file = lightning_dir.join(WALLET_DB_FILE_NAME);
+    let old_db_file = Path::new(WALLET_DB_FILE_NAME);
+    if !db_file.exists() && old_db_file.exists() {
+        warn!(
+            "Copying the wallet db from {} to {}, the old file is kept and can be removed",
+            fs::canonicalize(old_db_file)?.display(),
+            db_file.display()
+        );
+        fs::copy(old_db_file, &db_file)?;
+    }
+    Ok(db_file)
+}
+
+fn parse_seed(seed: &str) -> Result<[u8; 32]> {
+    hex::decode(seed)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid seed"))
+}
+
+fn write_seed_file(seed_file: &Path, seed: [u8; 32]) -> Result<()> {
+    info!("Writing seed to {}", seed_file.display());
+    let mut file = OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(seed_file)?;
+    file.write_all(hex::encode(seed).as_bytes())?;
     Ok(())
 }
@@ -0,0 +1,352 @@
+use super::*;
+
+// parameters of CLN's invoice RPC as seen by the rpc_command hook, positional or named:
+// invoice amount_msat label description [expiry] [fallbacks] [preimage]
+//         [exposeprivatechannels] [cltv] [deschashonly]
+
+const PARAM_NAMES: [&str; 9] = [
+    "amount_msat",
+    "label",
+    "description",
+    "expiry",
+    "fallbacks",
+    "preimage",
+    "exposeprivatechannels",
+    "cltv",
+    "deschashonly",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvoiceParams {
+    // None for "any" amount invoices
+    pub amount_msat: Option<u64>,
+    pub label: String,
+    pub description: String,
+    pub expiry: Option<u64>,
+    pub fallbacks: Vec<String>,
+    pub preimage: Option<[u8; 32]>,
+    pub expose_private_channels: ExposePrivateChannels,
+    pub cltv: Option<u32>,
+    pub deschashonly: bool,
+    // our own named param, not part of lightningd's signature
+    pub fallback_override: Option<FallbackOverride>,
+}
+
+// which private channels lightningd may put into route hints of the invoice
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExposePrivateChannels {
+    // lightningd adds hints when the public channels can't receive the amount
+    Auto,
+    Never,
+    All,
+    // short channel ids
+    Only(Vec<String>),
+}
+
+impl ExposePrivateChannels {
+    pub fn allows(&self, short_channel_id: Option<&str>) -> bool {
+        match self {
+            ExposePrivateChannels::Auto | ExposePrivateChannels::All => true,
+            ExposePrivateChannels::Never => false,
+            ExposePrivateChannels::Only(channels) => {
+                short_channel_id.is_some_and(|scid| channels.iter().any(|channel| channel == scid))
+            }
+        }
+    }
+}
+
+impl InvoiceParams {
+    pub fn parse(params: &serde_json::Value) -> Result<Self> {
+        let mut params = params.clone();
+        let fallback_override = match params
+            .as_object_mut()
+            .and_then(|params| params.remove(FALLBACK_OVERRIDE_PARAM))
+        {
+            Some(value) => Some(FallbackOverride::from_str(
+                value.as_str().unwrap_or_default(),
+            )?),
+            None => None,
+        };
+        let named = named_params(&params, &PARAM_NAMES)?;
+        // null is the same as leaving out an optional parameter
+        let get = |name: &str| named.get(name).filter(|value| !value.is_null());
+
+        let amount_msat = parse_amount_msat(
+            get("amount_msat").ok_or(anyhow!("Missing invoice parameter amount_msat"))?,
+        )?;
+        let label = match get("label") {
+            Some(serde_json::Value::String(label)) => label.clone(),
+            Some(serde_json::Value::Number(label)) => label.to_string(),
+            Some(other) => return Err(anyhow!("Invalid label: {}", other)),
+            None => return Err(anyhow!("Missing invoice parameter label")),
+        };
+        let description = get("description")
+            .ok_or(anyhow!("Missing invoice parameter description"))?
+            .as_str()
+            .ok_or(anyhow!("Invalid description"))?
+            .to_string();
+        let expiry = get("expiry")
+            .map(|expiry| parse_u64("expiry", expiry))
+            .transpose()?;
+        let fallbacks = match get("fallbacks") {
+            Some(serde_json::Value::Array(fallbacks)) => fallbacks
+                .iter()
+                .map(|fallback| {
+                    fallback
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or(anyhow!("Invalid fallback address: {}", fallback))
+                })
+                .collect::<Result<_>>()?,
+            Some(other) => return Err(anyhow!("Invalid fallbacks: {}", other)),
+            None => Vec::new(),
+        };
+        let preimage = match get("preimage") {
+            Some(preimage) => Some(
+                hex::decode(preimage.as_str().unwrap_or_default())
+                    .ok()
+                    .and_then(|preimage| preimage.try_into().ok())
+                    .ok_or(anyhow!("Invalid preimage: {}", preimage))?,
+            ),
+            None => None,
+        };
+        let expose_private_channels = match get("exposeprivatechannels") {
+            Some(serde_json::Value::Bool(true)) => ExposePrivateChannels::All,
+            Some(serde_json::Value::Bool(false)) => ExposePrivateChannels::Never,
+            Some(serde_json::Value::String(scid)) => {
+                ExposePrivateChannels::Only(vec![scid.clone()])
+            }
+            Some(serde_json::Value::Array(scids)) => ExposePrivateChannels::Only(
+                scids
+                    .iter()
+                    .map(|scid| {
+                        scid.as_str()
+                            .map(str::to_string)
+                            .ok_or(anyhow!("Invalid short channel id: {}", scid))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            Some(other) => return Err(anyhow!("Invalid exposeprivatechannels: {}", other)),
+            None => ExposePrivateChannels::Auto,
+        };
+        let cltv = get("cltv")
+            .map(|cltv| {
+                u32::try_from(parse_u64("cltv", cltv)?).map_err(|_| anyhow!("Invalid cltv"))
+            })
+            .transpose()?;
+        let deschashonly = match get("deschashonly") {
+            Some(deschashonly) => deschashonly
+                .as_bool()
+                .ok_or(anyhow!("Invalid deschashonly: {}", deschashonly))?,
+            None => false,
+        };
+
+        Ok(InvoiceParams {
+            amount_msat,
+            label,
+            description,
+            expiry,
+            fallbacks,
+            preimage,
+            expose_private_channels,
+            cltv,
+            deschashonly,
+            fallback_override,
+        })
+    }
+
+    // parameters we can't put into a replacement invoice, so the invoice call is refused
+    // instead of handing out an invoice that silently differs from the request
+    pub fn check_replaceable(&self, jit: bool) -> Result<()> {
+        if !self.fallbacks.is_empty() {
+            return Err(anyhow!(
+                "fallbacks can't be added to invoices replaced due to low inbound liquidity"
+            ));
+        }
+        // replacement invoices only carry the route hints they need themselves
+        if matches!(
+            self.expose_private_channels,
+            ExposePrivateChannels::All | ExposePrivateChannels::Only(_)
+        ) {
+            return Err(anyhow!(
+                "exposeprivatechannels isn't supported for invoices replaced due to low inbound liquidity"
+            ));
+        }
+        if jit {
+            return Ok(());
+        }
+        // the mint creates the invoice and chooses payment hash, cltv and description
+        for (name, given) in [
+            ("preimage", self.preimage.is_some()),
+            ("cltv", self.cltv.is_some()),
+            ("deschashonly", self.deschashonly),
+        ] {
+            if given {
+                return Err(anyhow!(
+                    "{} isn't supported for ecash invoices, the mint creates the invoice",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// params of an intercepted RPC call by name, positional params are named in signature order
+pub fn named_params(
+    params: &serde_json::Value,
+    names: &[&str],
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    match params {
+        serde_json::Value::Object(params) => {
+            if let Some(unknown) = params.keys().find(|name| !names.contains(&name.as_str())) {
+                return Err(anyhow!("Unknown parameter {}", unknown));
+            }
+            Ok(params.clone())
+        }
+        serde_json::Value::Array(params) => {
+            if params.len() > names.len() {
+                return Err(anyhow!("Too many parameters"));
+            }
+            Ok(names
+                .iter()
+                .zip(params)
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect())
+        }
+        other => Err(anyhow!("Invalid parameters: {}", other)),
+    }
+}
+
+// amounts are msat numbers or strings like "1000", "1000msat", "10sat", "0.0001btc" or "any"
+fn parse_amount_msat(amount: &serde_json::Value) -> Result<Option<u64>> {
+    let amount = match amount {
+        serde_json::Value::Number(amount) => {
+            return Ok(Some(
+                amount
+                    .as_u64()
+                    .ok_or(anyhow!("Invalid amount_msat: {}", amount))?,
+            ))
+        }
+        serde_json::Value::String(amount) => amount.trim(),
+        other => return Err(anyhow!("Invalid amount_msat: {}", other)),
+    };
+    let invalid = || anyhow!("Invalid amount_msat: {}", amount);
+    if amount == "any" {
+        return Ok(None);
+    }
+    let amount_msat = if let Some(msat) = amount.strip_suffix("msat") {
+        msat.parse::<u64>().map_err(|_| invalid())?
+    } else if let Some(sat) = amount.strip_suffix("sat") {
+        sat.parse::<u64>()
+            .ok()
+            .and_then(|sat| sat.checked_mul(1000))
+            .ok_or_else(invalid)?
+    } else if let Some(btc) = amount.strip_suffix("btc") {
+        // 1 btc has 11 decimal places of msat, parsed without floats to stay exact
+        let (whole, fraction) = btc.split_once('.').unwrap_or((btc, ""));
+        if fraction.len() > 11 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let whole = whole.parse::<u64>().map_err(|_| invalid())?;
+        let fraction = format!("{:0<11}", fraction).parse::<u64>().unwrap_or(0);
+        whole
+            .checked_mul(100_000_000_000)
+            .and_then(|whole| whole.checked_add(fraction))
+            .ok_or_else(invalid)?
+    } else {
+        amount.parse::<u64>().map_err(|_| invalid())?
+    };
+    Ok(Some(amount_msat))
+}
+
+// numbers may also be passed as strings by lightning-cli
+fn parse_u64(name: &str, value: &serde_json::Value) -> Result<u64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_u64(),
+        serde_json::Value::String(number) => number.parse().ok(),
+        _ => None,
+    }
+    .ok_or(anyhow!("Invalid {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(amount: &str) -> Result<Option<u64>> {
+        parse_amount_msat(&json!(amount))
+    }
+
+    #[test]
+    fn parses_amounts() {
+        assert_eq!(amount("1000").unwrap(), Some(1000));
+        assert_eq!(amount("1000msat").unwrap(), Some(1000));
+        assert_eq!(amount("10sat").unwrap(), Some(10_000));
+        assert_eq!(amount("0.00000000001btc").unwrap(), Some(1));
+        assert_eq!(amount("1.5btc").unwrap(), Some(150_000_000_000));
+        assert_eq!(amount("any").unwrap(), None);
+        assert_eq!(parse_amount_msat(&json!(2000)).unwrap(), Some(2000));
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        // u64::MAX / 1000 + 1 sat
+        assert!(amount("18446744073709552sat").is_err());
+        assert!(amount("184467440.73709551616btc").is_err());
+        assert!(amount("0.000000000001btc").is_err());
+        assert!(amount("1.-5btc").is_err());
+        assert!(amount("-1").is_err());
+        assert!(parse_amount_msat(&json!(-1)).is_err());
+    }
+
+    #[test]
+    fn names_positional_params() {
+        let params = InvoiceParams::parse(&json!([1000, "label", "desc", 60])).unwrap();
+        assert_eq!(params.amount_msat, Some(1000));
+        assert_eq!(params.label, "label");
+        assert_eq!(params.description, "desc");
+        assert_eq!(params.expiry, Some(60));
+
+        let too_many = json!([
+            1000,
+            "label",
+            "desc",
+            60,
+            [],
+            null,
+            false,
+            18,
+            false,
+            "extra"
+        ]);
+        assert!(named_params(&too_many, &PARAM_NAMES).is_err());
+        assert!(InvoiceParams::parse(&too_many).is_err());
+    }
+
+    #[test]
+    fn treats_null_as_missing() {
+        let params = InvoiceParams::parse(&json!({
+            "amount_msat": "any",
+            "label": 7,
+            "description": "desc",
+            "expiry": null,
+            "fallbacks": null,
+            "preimage": null,
+            "exposeprivatechannels": null,
+            "cltv": null,
+            "deschashonly": null,
+        }))
+        .unwrap();
+        assert_eq!(params.amount_msat, None);
+        assert_eq!(params.label, "7");
+        assert_eq!(params.expiry, None);
+        assert!(params.fallbacks.is_empty());
+        assert_eq!(params.preimage, None);
+        assert_eq!(params.expose_private_channels, ExposePrivateChannels::Auto);
+        assert_eq!(params.cltv, None);
+        assert!(!params.deschashonly);
+        // required params can't be null
+        assert!(InvoiceParams::parse(&json!([null, "label", "desc"])).is_err());
+    }
+}
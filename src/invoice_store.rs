@@ -0,0 +1,461 @@
+use super::*;
+
+// replacement invoices are unknown to lightningd, so we keep records of them and answer
+// listinvoices, waitinvoice, waitanyinvoice and delinvoice for them in the rpc_command hook
+
+// datastore namespace of the invoice records, keyed by label
+const INVOICES_NAMESPACE: &str = "invoices";
+const PAY_INDEX_KEY: &str = "pay_index";
+// custom notification sent when proofs of an ecash invoice are minted, like invoice_payment
+pub const INVOICE_PAID_NOTIFICATION: &str = "kickstart_invoice_paid";
+
+// lightningd's error codes of the invoice RPCs
+const INVOICE_LABEL_ALREADY_EXISTS: i32 = 900;
+const INVOICE_EXPIRED_DURING_WAIT: i32 = 903;
+const INVOICE_WAIT_TIMED_OUT: i32 = 904;
+const INVOICE_STATUS_UNEXPECTED: i32 = 905;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InvoiceRecord {
+    pub label: String,
+    pub bolt11: String,
+    pub payment_hash: String,
+    pub amount_msat: u64,
+    pub description: Option<String>,
+    // reserved in lightningd's numbering by a placeholder invoice, see `reserve_created_index`
+    #[serde(alias = "kickstart_created_index")]
+    pub created_index: u64,
+    pub expires_at: u64,
+    // set for ecash invoices, JIT channel invoices are paid through our node
+    pub mint_quote_id: Option<String>,
+    pub mint_url: Option<String>,
+    // counted by the plugin, not comparable with the pay_index of lightningd's invoices
+    #[serde(alias = "pay_index")]
+    pub kickstart_pay_index: Option<u64>,
+    pub amount_received_msat: Option<u64>,
+    pub paid_at: Option<u64>,
+    // why the liquidity policy replaced the invoice
+    #[serde(default)]
+    pub fallback_reason: Option<String>,
+}
+
+impl InvoiceRecord {
+    fn status(&self) -> &'static str {
+        if self.paid_at.is_some() {
+            "paid"
+        } else if self.expires_at <= unix_time() {
+            "expired"
+        } else {
+            "unpaid"
+        }
+    }
+
+    // the invoice as listed by lightningd
+    fn to_json(&self) -> serde_json::Value {
+        let mut invoice = json!({
+            "label": self.label,
+            "bolt11": self.bolt11,
+            "payment_hash": self.payment_hash,
+            "status": self.status(),
+            "amount_msat": self.amount_msat,
+            "expires_at": self.expires_at,
+            "created_index": self.created_index,
+        });
+        if let Some(description) = &self.description {
+            invoice["description"] = json!(description);
+        }
+        if let (Some(pay_index), Some(amount_received_msat), Some(paid_at)) = (
+            self.kickstart_pay_index,
+            self.amount_received_msat,
+            self.paid_at,
+        ) {
+            invoice["kickstart_pay_index"] = json!(pay_index);
+            invoice["amount_received_msat"] = json!(amount_received_msat);
+            invoice["paid_at"] = json!(paid_at);
+        }
+        invoice
+    }
+}
+
+pub struct InvoiceStore {
+    // pay index of the last paid record, waiters are woken when it changes
+    last_pay_index: watch::Sender<u64>,
+}
+
+impl InvoiceStore {
+    pub fn new() -> Self {
+        InvoiceStore {
+            last_pay_index: watch::Sender::new(0),
+        }
+    }
+
+    pub async fn insert(&self, record: &InvoiceRecord) -> Result<()> {
+        datastore_put(INVOICES_NAMESPACE, &record.label, record).await
+    }
+
+    async fn get(&self, label: &str) -> Result<Option<InvoiceRecord>> {
+        datastore_get(INVOICES_NAMESPACE, label).await
+    }
+
+    async fn list(&self) -> Result<Vec<InvoiceRecord>> {
+        let mut records: Vec<InvoiceRecord> = datastore_list(INVOICES_NAMESPACE).await?;
+        records.sort_by_key(|record| record.created_index);
+        Ok(records)
+    }
+
+    // labels have to be unique across our records and lightningd's invoices
+    pub async fn check_label_unused(&self, label: &str) -> Result<(), serde_json::Value> {
+        let lightningd_invoices = match send_rpc_request(
+            ListinvoicesRequest {
+                label: Some(label.to_string()),
+                invstring: None,
+                payment_hash: None,
+                offer_id: None,
+                index: None,
+                start: None,
+                limit: None,
+            }
+            .into(),
+        )
+        .await
+        {
+            Ok(Response::ListInvoices(response)) => response.invoices.len(),
+            Ok(_) => return Err(error_response(-1, "Unexpected response".to_string())),
+            Err(e) => return Err(rpc_error_response(e)),
+        };
+        let exists = match self.get(label).await {
+            Ok(record) => record.is_some() || lightningd_invoices > 0,
+            Err(e) => return Err(rpc_error_response(e)),
+        };
+        if exists {
+            return Err(error_response(
+                INVOICE_LABEL_ALREADY_EXISTS,
+                "Duplicate label".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // returns the record if it wasn't marked as paid before
+    pub async fn mark_quote_paid(
+        &self,
+        mint_quote_id: &str,
+        amount_received_msat: u64,
+    ) -> Option<InvoiceRecord> {
+        self.mark_paid(
+            |record| record.mint_quote_id.as_deref() == Some(mint_quote_id),
+            amount_received_msat,
+        )
+        .await
+    }
+
+    pub async fn mark_hash_paid(
+        &self,
+        payment_hash: &str,
+        amount_received_msat: u64,
+    ) -> Option<InvoiceRecord> {
+        self.mark_paid(
+            |record| record.payment_hash == payment_hash,
+            amount_received_msat,
+        )
+        .await
+    }
+
+    // quotes without a record, e.g. for moving funds between mints, are ignored
+    async fn mark_paid(
+        &self,
+        matches: impl Fn(&InvoiceRecord) -> bool,
+        amount_received_msat: u64,
+    ) -> Option<InvoiceRecord> {
+        let result = async {
+            let Some(mut record) = self
+                .list()
+                .await?
+                .into_iter()
+                .find(|record| matches(record))
+            else {
+                return Ok(None);
+            };
+            if record.paid_at.is_some() {
+                return Ok(None);
+            }
+            let pay_index = datastore_increment(COUNTERS_NAMESPACE, PAY_INDEX_KEY).await?;
+            record.kickstart_pay_index = Some(pay_index);
+            record.amount_received_msat = Some(amount_received_msat);
+            record.paid_at = Some(unix_time());
+            self.insert(&record).await?;
+            info!("Invoice {} paid", record.label);
+            self.last_pay_index.send_replace(pay_index);
+            Ok::<_, Error>(Some(record))
+        };
+        result.await.unwrap_or_else(|e| {
+            error!("Error recording invoice payment: {}", e);
+            None
+        })
+    }
+
+    async fn list_invoices(&self, params: &serde_json::Value) -> Result<serde_json::Value> {
+        let params = named_params(
+            params,
+            &[
+                "label",
+                "invstring",
+                "payment_hash",
+                "offer_id",
+                "index",
+                "start",
+                "limit",
+            ],
+        )?;
+        let get = |name: &str| params.get(name).filter(|value| !value.is_null());
+        let filters = [
+            ("label", get("label")),
+            ("invstring", get("invstring")),
+            ("payment_hash", get("payment_hash")),
+        ];
+        if filters.iter().any(|(_, value)| value.is_some()) {
+            let record = self.list().await?.into_iter().find(|record| {
+                filters.iter().all(|(name, value)| match (name, value) {
+                    (_, None) => true,
+                    (&"label", Some(label)) => {
+                        label.as_str() == Some(&record.label)
+                            || label.as_u64().map(|label| label.to_string())
+                                == Some(record.label.clone())
+                    }
+                    (&"invstring", Some(invstring)) => invstring.as_str() == Some(&record.bolt11),
+                    (_, Some(payment_hash)) => payment_hash.as_str() == Some(&record.payment_hash),
+                })
+            });
+            return Ok(match record {
+                Some(record) => {
+                    json!({"return": {"result": {"invoices": [record.to_json()]}}})
+                }
+                None => json!({"result": "continue"}),
+            });
+        }
+        // paginated listings only cover lightningd's invoices, our index space differs
+        if ["offer_id", "index", "start", "limit"]
+            .iter()
+            .any(|name| get(name).is_some())
+        {
+            return Ok(json!({"result": "continue"}));
+        }
+        let records = self.list().await?;
+        if records.is_empty() {
+            return Ok(json!({"result": "continue"}));
+        }
+        // listing from the first created index lists everything and, being paginated, passes
+        // our own hook unchanged, whichever calls run concurrently
+        let request = ListinvoicesRequest {
+            label: None,
+            invstring: None,
+            payment_hash: None,
+            offer_id: None,
+            index: Some(ListinvoicesIndex::CREATED),
+            start: Some(0),
+            limit: None,
+        };
+        let mut result = match send_rpc_request(request.into()).await? {
+            Response::ListInvoices(response) => serde_json::to_value(response)?,
+            _ => return Err(anyhow!("Unexpected response")),
+        };
+        let invoices = result["invoices"]
+            .as_array_mut()
+            .ok_or(anyhow!("Unexpected response"))?;
+        invoices.extend(records.iter().map(InvoiceRecord::to_json));
+        Ok(json!({"return": {"result": result}}))
+    }
+
+    async fn wait_invoice(&self, params: &serde_json::Value) -> Result<serde_json::Value> {
+        let params = named_params(params, &["label"])?;
+        let label = match params.get("label") {
+            Some(serde_json::Value::String(label)) => label.clone(),
+            Some(serde_json::Value::Number(label)) => label.to_string(),
+            _ => return Ok(json!({"result": "continue"})),
+        };
+        if self.get(&label).await?.is_none() {
+            return Ok(json!({"result": "continue"}));
+        }
+        let mut paid = self.last_pay_index.subscribe();
+        loop {
+            paid.borrow_and_update();
+            let Some(record) = self.get(&label).await? else {
+                return Err(anyhow!("Invoice {} deleted during wait", label));
+            };
+            match record.status() {
+                "paid" => return Ok(json!({"return": {"result": record.to_json()}})),
+                "expired" => {
+                    return Ok(error_response(
+                        INVOICE_EXPIRED_DURING_WAIT,
+                        "invoice expired during wait".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+            let expires_in = Duration::from_secs(record.expires_at.saturating_sub(unix_time()));
+            tokio::select! {
+                _ = paid.changed() => {}
+                _ = tokio::time::sleep(expires_in) => {}
+            }
+        }
+    }
+
+    // races lightningd's paid invoices against our records, ecash invoices have no pay_index
+    // in lightningd's numbering, so they are only returned when paid during the wait
+    async fn wait_any_invoice(&self, params: &serde_json::Value) -> Result<serde_json::Value> {
+        let params = named_params(params, &["lastpay_index", "timeout"])?;
+        let get_u64 = |name: &str| params.get(name).and_then(|value| value.as_u64());
+        let lastpay_index = get_u64("lastpay_index");
+        let timeout = get_u64("timeout");
+        // nothing can be paid during a wait without timeout, this also lets our own calls pass
+        if timeout == Some(0) {
+            return Ok(json!({"result": "continue"}));
+        }
+        let deadline =
+            timeout.map(|timeout| tokio::time::Instant::now() + Duration::from_secs(timeout));
+        let mut paid = self.last_pay_index.subscribe();
+        let last_pay_index = *paid.borrow_and_update();
+        loop {
+            // read before looking, so an invoice paid in between ends the wait below right away
+            let updated_index = wait_invoices_updated(0).await?;
+            let request = WaitanyinvoiceRequest {
+                lastpay_index,
+                timeout: Some(0),
+            };
+            match send_rpc_request(request.into()).await {
+                Ok(Response::WaitAnyInvoice(response)) => {
+                    return Ok(json!({"return": {"result": serde_json::to_value(response)?}}));
+                }
+                Ok(_) => return Err(anyhow!("Unexpected response")),
+                Err(e)
+                    if e.downcast_ref::<RpcError>()
+                        .is_some_and(|e| e.code == Some(INVOICE_WAIT_TIMED_OUT)) => {}
+                Err(e) => return Ok(rpc_error_response(e)),
+            }
+            let timed_out = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                updated = wait_invoices_updated(updated_index + 1) => {
+                    updated?;
+                }
+                changed = paid.changed() => {
+                    changed?;
+                    if let Some(record) = self
+                        .list()
+                        .await?
+                        .into_iter()
+                        .filter(|record| {
+                            record.kickstart_pay_index.is_some_and(|index| index > last_pay_index)
+                        })
+                        .min_by_key(|record| record.kickstart_pay_index)
+                    {
+                        return Ok(json!({"return": {"result": record.to_json()}}));
+                    }
+                }
+                _ = timed_out => {
+                    return Ok(error_response(INVOICE_WAIT_TIMED_OUT, "Timed out".to_string()));
+                }
+            }
+        }
+    }
+
+    async fn del_invoice(&self, params: &serde_json::Value) -> Result<serde_json::Value> {
+        let params = named_params(params, &["label", "status", "desconly"])?;
+        let label = match params.get("label") {
+            Some(serde_json::Value::String(label)) => label.clone(),
+            Some(serde_json::Value::Number(label)) => label.to_string(),
+            _ => return Ok(json!({"result": "continue"})),
+        };
+        let Some(mut record) = self.get(&label).await? else {
+            return Ok(json!({"result": "continue"}));
+        };
+        let status = params["status"].as_str().unwrap_or_default();
+        if status != record.status() {
+            return Ok(error_response(
+                INVOICE_STATUS_UNEXPECTED,
+                format!("Invoice status is {} not {}", record.status(), status),
+            ));
+        }
+        if params
+            .get("desconly")
+            .and_then(|desconly| desconly.as_bool())
+            == Some(true)
+        {
+            record.description = None;
+            self.insert(&record).await?;
+        } else {
+            // a pending ecash quote is still minted, only the record is gone
+            datastore_delete(INVOICES_NAMESPACE, &label).await?;
+        }
+        Ok(json!({"return": {"result": record.to_json()}}))
+    }
+}
+
+// serves the invoice RPCs from our records, everything else continues to lightningd
+pub async fn invoice_rpc_handler(
+    store: &InvoiceStore,
+    method: &str,
+    params: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let result = match method {
+        "listinvoices" => store.list_invoices(params).await,
+        "waitinvoice" => store.wait_invoice(params).await,
+        "waitanyinvoice" => store.wait_any_invoice(params).await,
+        "delinvoice" => store.del_invoice(params).await,
+        _ => return Ok(json!({"result": "continue"})),
+    };
+    // lightningd answers invalid calls with its own errors
+    result.or_else(|e| {
+        debug!("Not handling {} call: {}", method, e);
+        Ok(json!({"result": "continue"}))
+    })
+}
+
+// lightningd's updated index of invoices (bumped when one is paid or expires) once it
+// reaches nextvalue, the current one for 0
+async fn wait_invoices_updated(nextvalue: u64) -> Result<u64> {
+    let request = WaitRequest {
+        subsystem: WaitSubsystem::INVOICES,
+        indexname: WaitIndexname::UPDATED,
+        nextvalue,
+    };
+    match send_rpc_request(request.into()).await? {
+        Response::Wait(response) => Ok(response.updated.unwrap_or(0)),
+        _ => Err(anyhow!("Unexpected response")),
+    }
+}
+
+pub async fn notify_invoice_paid(p: &Plugin<PluginState>, record: &InvoiceRecord) {
+    let payload = json!({
+        "label": record.label,
+        "payment_hash": record.payment_hash,
+        "amount_msat": record.amount_received_msat,
+        "mint_url": record.mint_url,
+        "kickstart_pay_index": record.kickstart_pay_index,
+    });
+    if let Err(e) = p
+        .send_custom_notification(INVOICE_PAID_NOTIFICATION.to_string(), payload)
+        .await
+    {
+        warn!(
+            "Error sending {} notification: {}",
+            INVOICE_PAID_NOTIFICATION, e
+        );
+    }
+}
+
+pub fn error_response(code: i32, message: String) -> serde_json::Value {
+    json!({"return": {"error": {"code": code, "message": message}}})
+}
+
+// keeps the code of errors returned by lightningd
+fn rpc_error_response(e: Error) -> serde_json::Value {
+    match e.downcast_ref::<RpcError>() {
+        Some(rpc_error) => error_response(rpc_error.code.unwrap_or(-1), rpc_error.message.clone()),
+        None => error_response(-1, e.to_string()),
+    }
+}
@@ -0,0 +1,233 @@
+use super::*;
+
+// decides whether an invoice is replaced by a fallback invoice, kept free of RPC calls
+// so policies can be tested without lightningd
+
+// named invoice param to force or prevent the fallback for a single call,
+// removed before the call is passed on to lightningd
+pub const FALLBACK_OVERRIDE_PARAM: &str = "kickstart_fallback";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FallbackOverride {
+    Always,
+    Never,
+}
+
+impl FromStr for FallbackOverride {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "always" => Ok(FallbackOverride::Always),
+            "never" => Ok(FallbackOverride::Never),
+            other => Err(anyhow!(
+                "Invalid {}: {}, use always or never",
+                FALLBACK_OVERRIDE_PARAM,
+                other
+            )),
+        }
+    }
+}
+
+// what the policy gets to know about the invoice call
+#[derive(Debug, Clone)]
+pub struct FallbackRequest<'a> {
+    pub amount_msat: u64,
+    pub label: &'a str,
+    pub receivable_msat: u64,
+    pub fallback_override: Option<FallbackOverride>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackDecision {
+    pub fallback: bool,
+    // logged and stored with replaced invoices
+    pub reason: String,
+}
+
+impl FallbackDecision {
+    fn direct(reason: String) -> Self {
+        FallbackDecision {
+            fallback: false,
+            reason,
+        }
+    }
+
+    fn fallback(reason: String) -> Self {
+        FallbackDecision {
+            fallback: true,
+            reason,
+        }
+    }
+}
+
+pub trait LiquidityPolicy {
+    fn decide(&self, request: &FallbackRequest) -> FallbackDecision;
+}
+
+// falls back when the amount exceeds the usable share of the receivable capacity,
+// within the configured amount range
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThresholdPolicy {
+    // share of the receivable capacity considered usable
+    pub buffer_factor: f64,
+    // kept free on top of the buffer factor
+    pub margin_msat: u64,
+    pub min_fallback_msat: Option<u64>,
+    pub max_fallback_msat: Option<u64>,
+    // invoices with these label prefixes are never replaced
+    pub opt_out_label_prefixes: Vec<String>,
+}
+
+impl LiquidityPolicy for ThresholdPolicy {
+    fn decide(&self, request: &FallbackRequest) -> FallbackDecision {
+        match request.fallback_override {
+            Some(FallbackOverride::Always) => {
+                return FallbackDecision::fallback("forced by the invoice call".to_string())
+            }
+            Some(FallbackOverride::Never) => {
+                return FallbackDecision::direct("prevented by the invoice call".to_string())
+            }
+            None => {}
+        }
+        if let Some(prefix) = self
+            .opt_out_label_prefixes
+            .iter()
+            .find(|prefix| request.label.starts_with(prefix.as_str()))
+        {
+            return FallbackDecision::direct(format!("label has opt-out prefix {}", prefix));
+        }
+        let usable_msat = ((request.receivable_msat as f64 * self.buffer_factor) as u64)
+            .saturating_sub(self.margin_msat);
+        if request.amount_msat <= usable_msat {
+            return FallbackDecision::direct(format!(
+                "{} msat fit into {} msat usable inbound liquidity",
+                request.amount_msat, usable_msat
+            ));
+        }
+        if let Some(min_fallback_msat) = self.min_fallback_msat {
+            if request.amount_msat < min_fallback_msat {
+                return FallbackDecision::direct(format!(
+                    "{} msat below the minimum fallback amount of {} msat",
+                    request.amount_msat, min_fallback_msat
+                ));
+            }
+        }
+        if let Some(max_fallback_msat) = self.max_fallback_msat {
+            if request.amount_msat > max_fallback_msat {
+                return FallbackDecision::direct(format!(
+                    "{} msat above the maximum fallback amount of {} msat",
+                    request.amount_msat, max_fallback_msat
+                ));
+            }
+        }
+        FallbackDecision::fallback(format!(
+            "{} msat exceed {} msat usable inbound liquidity",
+            request.amount_msat, usable_msat
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ThresholdPolicy {
+        ThresholdPolicy {
+            buffer_factor: 0.9,
+            margin_msat: 0,
+            min_fallback_msat: None,
+            max_fallback_msat: None,
+            opt_out_label_prefixes: Vec::new(),
+        }
+    }
+
+    fn request(amount_msat: u64, receivable_msat: u64) -> FallbackRequest<'static> {
+        FallbackRequest {
+            amount_msat,
+            label: "label",
+            receivable_msat,
+            fallback_override: None,
+        }
+    }
+
+    #[test]
+    fn falls_back_above_buffered_capacity() {
+        assert!(!policy().decide(&request(900_000, 1_000_000)).fallback);
+        assert!(policy().decide(&request(900_001, 1_000_000)).fallback);
+        assert!(policy().decide(&request(1, 0)).fallback);
+    }
+
+    #[test]
+    fn margin_is_kept_free() {
+        let policy = ThresholdPolicy {
+            margin_msat: 100_000,
+            ..policy()
+        };
+        assert!(!policy.decide(&request(800_000, 1_000_000)).fallback);
+        assert!(policy.decide(&request(800_001, 1_000_000)).fallback);
+        // a margin above the capacity doesn't underflow
+        assert!(policy.decide(&request(1, 10_000)).fallback);
+    }
+
+    #[test]
+    fn amount_range_limits_fallback() {
+        let policy = ThresholdPolicy {
+            min_fallback_msat: Some(10_000),
+            max_fallback_msat: Some(1_000_000),
+            ..policy()
+        };
+        assert!(!policy.decide(&request(9_999, 0)).fallback);
+        assert!(policy.decide(&request(10_000, 0)).fallback);
+        assert!(policy.decide(&request(1_000_000, 0)).fallback);
+        assert!(!policy.decide(&request(1_000_001, 0)).fallback);
+    }
+
+    #[test]
+    fn opt_out_label_prefix() {
+        let policy = ThresholdPolicy {
+            opt_out_label_prefixes: vec!["direct-".to_string()],
+            ..policy()
+        };
+        let opted_out = FallbackRequest {
+            label: "direct-42",
+            ..request(1_000, 0)
+        };
+        let decision = policy.decide(&opted_out);
+        assert!(!decision.fallback);
+        assert!(decision.reason.contains("direct-"));
+        assert!(policy.decide(&request(1_000, 0)).fallback);
+    }
+
+    #[test]
+    fn override_wins() {
+        let policy = ThresholdPolicy {
+            opt_out_label_prefixes: vec!["label".to_string()],
+            max_fallback_msat: Some(1),
+            ..policy()
+        };
+        let always = FallbackRequest {
+            fallback_override: Some(FallbackOverride::Always),
+            ..request(1_000, 1_000_000)
+        };
+        assert!(policy.decide(&always).fallback);
+        let never = FallbackRequest {
+            fallback_override: Some(FallbackOverride::Never),
+            ..request(1_000, 0)
+        };
+        assert!(!policy.decide(&never).fallback);
+    }
+
+    #[test]
+    fn parses_override() {
+        assert_eq!(
+            FallbackOverride::from_str("always").unwrap(),
+            FallbackOverride::Always
+        );
+        assert_eq!(
+            FallbackOverride::from_str("never").unwrap(),
+            FallbackOverride::Never
+        );
+        assert!(FallbackOverride::from_str("sometimes").is_err());
+    }
+}
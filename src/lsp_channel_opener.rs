@@ -2,12 +2,67 @@ use std::time::Duration;
 
 use super::*;
 
-// currently using zeus olympus ( i think LSP1 spec)
+// LSPS1 client, either talking to the REST API of zeus olympus or to any
+// LSPS1 capable node over BOLT8 custom messages (LSPS0 JSON-RPC)
 // semi professional llm API implementation -> warn!("hackathon project")
 
-const BASE_URL: &str = "https://mutinynet-lsps1.lnolymp.us";
+// share of the ecash balance we are willing to spend on a channel, the rest covers mint fees
+const CHANNEL_FEE_BUFFER_FACTOR: f64 = 0.9;
+// datastore namespace of the LSPS1 orders, keyed by order id
+const LSP_ORDERS_NAMESPACE: &str = "lsp_orders";
+// get_order polling backs off from the first to the second interval while nothing changes
+const ORDER_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_ORDER_POLL_INTERVAL: Duration = Duration::from_secs(600);
+// refund addresses of failed paid orders are checked this often, for this long after the order ended
+const REFUND_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+const REFUND_WATCH_SECS: u64 = 30 * 24 * 3600;
+// an order still open this long after its invoice expired is given up as failed, so an LSP
+// that stops answering can't hold a slot of max-concurrent-orders forever
+const ORDER_TRACKING_GRACE_SECS: u64 = 3 * 24 * 3600;
+// LSPS1 only reveals prices in orders, until we ordered from an LSP its price is guessed
+// as a base fee plus a share of the channel size
+const DEFAULT_FEE_BASE_SAT: u64 = 5_000;
+const DEFAULT_FEE_PPM: u64 = 10_000;
+// estimates are redone this often to notice LSPs coming and going
+const ESTIMATE_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
 
-#[derive(Debug, Deserialize)]
+// what the channel manager is currently up to, reported by kickstart-status
+#[derive(Debug, Default, Serialize)]
+pub struct ChannelManagerStatus {
+    lsp: Option<String>,
+    lsp_info: Option<GetInfoResponse>,
+    target_channel_size_sat: Option<u64>,
+    estimated_cost_sat: Option<u64>,
+    // unix time of the last estimate
+    estimated_at: Option<u64>,
+    // estimated cost of every LSP accepting the target channel size, cheapest first
+    estimates: Vec<CostEstimate>,
+    // paid orders waiting for their channel, by order id
+    in_flight_orders: HashMap<String, OrderRecord>,
+    // failed paid orders whose refund hasn't arrived yet, by order id
+    awaiting_refund: HashMap<String, OrderRecord>,
+}
+
+impl ChannelManagerStatus {
+    // sats missing in the ecash wallet before a channel purchase is triggered
+    pub fn sats_needed(&self, ecash_balance_sat: u64) -> Option<u64> {
+        let estimated_cost = self.estimated_cost_sat?;
+        // smallest balance passing the check of try_buy_channel, a balance of exactly
+        // cost / factor isn't enough as the buffer has to be strictly larger than the cost
+        let mut required_balance = (estimated_cost as f64 / CHANNEL_FEE_BUFFER_FACTOR) as u64;
+        while !balance_covers_cost(required_balance, estimated_cost) {
+            required_balance += 1;
+        }
+        Some(required_balance.saturating_sub(ecash_balance_sat))
+    }
+}
+
+// whether the share of the balance we spend on channels pays for a channel of this cost
+fn balance_covers_cost(ecash_balance_sat: u64, cost_sat: u64) -> bool {
+    ecash_balance_sat as f64 * CHANNEL_FEE_BUFFER_FACTOR > cost_sat as f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct GetInfoResponse {
     max_channel_balance_sat: String,
     max_channel_expiry_blocks: u32,
@@ -21,6 +76,8 @@ struct GetInfoResponse {
     min_onchain_payment_size_sat: Option<String>,
     min_required_channel_confirmations: u32,
     supports_zero_channel_reserve: bool,
+    // only returned by the REST API, over BOLT8 we already know the node
+    #[serde(default)]
     uris: Vec<String>,
 }
 
@@ -34,10 +91,12 @@ struct CreateOrderRequest {
     token: String,
     refund_onchain_address: String,
     announce_channel: bool,
-    public_key: String,
+    // only needed by the REST API, over BOLT8 the LSP knows who is asking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CreateOrderResponse {
     announce_channel: bool,
     channel: Option<serde_json::Value>,
@@ -52,12 +111,12 @@ struct CreateOrderResponse {
     token: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Payment {
     bolt11: Bolt11,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Bolt11 {
     order_total_sat: String,
     fee_total_sat: String,
@@ -66,26 +125,105 @@ struct Bolt11 {
     expires_at: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum OrderState {
+    Created,
+    // set right before paying, so an order is never paid twice, not even after a crash
+    Paid,
+    ChannelPending,
+    Completed,
+    Failed,
+    Refunded,
+}
+
+impl OrderState {
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            OrderState::Completed | OrderState::Failed | OrderState::Refunded
+        )
+    }
+}
+
+// an order persisted in the datastore so it is tracked across restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OrderRecord {
+    lsp: String,
+    state: OrderState,
+    // latest answer of the LSP
+    order: CreateOrderResponse,
+    updated_at: u64,
+    // on-chain address of our node the LSP refunds to if the order fails
+    #[serde(default)]
+    refund_address: Option<String>,
+    // set once the melt succeeded or the LSP reports our payment as received
+    #[serde(default)]
+    paid_at: Option<u64>,
+    // sum of all outputs received at the refund address
+    #[serde(default)]
+    refund_received_sat: u64,
+}
+
+impl OrderRecord {
+    // paid orders that failed are expected to be refunded on-chain, refunded orders got
+    // their lightning payment back already
+    fn awaits_refund(&self) -> bool {
+        self.refund_address.is_some()
+            && self.paid_at.is_some()
+            && self.state == OrderState::Failed
+            && self.refund_received_sat == 0
+            && self.updated_at + REFUND_WATCH_SECS > unix_time()
+    }
+
+    // unix time after which the order isn't tracked anymore
+    fn tracking_deadline(&self) -> u64 {
+        Bolt11Invoice::from_str(&self.order.payment.bolt11.invoice)
+            .ok()
+            .and_then(|invoice| invoice.expires_at())
+            .map_or(self.updated_at, |expires_at| expires_at.as_secs())
+            .saturating_add(ORDER_TRACKING_GRACE_SECS)
+    }
+
+    async fn set_state(&mut self, state: OrderState) -> Result<()> {
+        if state != self.state {
+            info!(
+                "LSP order {} at {}: {:?} -> {:?}",
+                self.order.order_id, self.lsp, self.state, state
+            );
+        }
+        self.state = state;
+        self.save().await
+    }
+
+    async fn save(&mut self) -> Result<()> {
+        self.updated_at = unix_time();
+        datastore_put(LSP_ORDERS_NAMESPACE, &self.order.order_id, &*self).await
+    }
+}
+
 struct OlympusLspClient {
     client: reqwest::Client,
+    base_url: String,
 }
 
 impl OlympusLspClient {
-    fn new() -> Self {
+    fn new(base_url: &str) -> Self {
         OlympusLspClient {
             client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
         }
     }
 
     async fn get_info(&self) -> Result<GetInfoResponse> {
-        let url = format!("{}/api/v1/get_info", BASE_URL);
+        let url = format!("{}/api/v1/get_info", self.base_url);
         let response = self.client.get(&url).send().await?;
         let info: GetInfoResponse = response.json().await?;
         Ok(info)
     }
 
     async fn create_order(&self, request: CreateOrderRequest) -> Result<CreateOrderResponse> {
-        let url = format!("{}/api/v1/create_order", BASE_URL);
+        let url = format!("{}/api/v1/create_order", self.base_url);
         let response = self.client.post(&url).json(&request).send().await?;
         if response.status() != 200 {
             return Err(anyhow!(
@@ -98,41 +236,133 @@ impl OlympusLspClient {
     }
 
     async fn get_order(&self, order_id: &str) -> Result<CreateOrderResponse> {
-        let url = format!("{}/api/v1/get_order?order_id={}", BASE_URL, order_id);
+        let url = format!("{}/api/v1/get_order?order_id={}", self.base_url, order_id);
         let response = self.client.get(&url).send().await?;
         let order: CreateOrderResponse = response.json().await?;
         Ok(order)
     }
+}
 
-    async fn get_estimated_cost(&self, size_sat: u64, node_pk: &str) -> Result<u64> {
-        let info = self.get_info().await?;
-        let create_order_request = CreateOrderRequest {
-            lsp_balance_sat: size_sat.to_string(),
-            client_balance_sat: "0".to_string(),
-            required_channel_confirmations: info.min_required_channel_confirmations,
-            funding_confirms_within_blocks: info.min_funding_confirms_within_blocks,
-            channel_expiry_blocks: info.max_channel_expiry_blocks,
-            token: "".to_string(),
-            refund_onchain_address: "".to_string(),
-            announce_channel: true,
-            public_key: node_pk.to_string(),
-        };
-        let create_order_response = self.create_order(create_order_request).await?;
-        Ok(create_order_response
-            .payment
-            .bolt11
-            .order_total_sat
-            .parse::<u64>()?)
+// LSPS1 over LSPS0 JSON-RPC, sent to the LSP node as BOLT8 custom messages
+struct Bolt8LspClient {
+    node_id: String,
+    transport: Arc<Lsps0Transport>,
+}
+
+impl Bolt8LspClient {
+    async fn get_info(&self) -> Result<GetInfoResponse> {
+        self.transport
+            .call(&self.node_id, "lsps1.get_info", json!({}))
+            .await
+    }
+
+    async fn create_order(&self, request: CreateOrderRequest) -> Result<CreateOrderResponse> {
+        self.transport
+            .call(&self.node_id, "lsps1.create_order", request)
+            .await
+    }
+
+    async fn get_order(&self, order_id: &str) -> Result<CreateOrderResponse> {
+        self.transport
+            .call(
+                &self.node_id,
+                "lsps1.get_order",
+                json!({ "order_id": order_id }),
+            )
+            .await
     }
 }
 
-async fn open_lsp_channel(
-    size_sat: u64,
+// the transports share the same interface so the channel logic doesn't care which one is used
+enum LspClient {
+    Rest(OlympusLspClient),
+    Bolt8 {
+        client: Bolt8LspClient,
+        address: (String, String, u16),
+    },
+}
+
+impl LspClient {
+    // all configured LSPs, the config falls back to the default LSP of the network
+    fn all_from_config(config: &Config, transport: Arc<Lsps0Transport>) -> Result<Vec<Arc<Self>>> {
+        let mut clients = Vec::new();
+        for uri in &config.lsp_nodes {
+            let address = parse_lsp_host(vec![uri.clone()]).pop().ok_or(anyhow!(
+                "Invalid {} entry: {}",
+                OPT_LSP_NODE.name,
+                uri
+            ))?;
+            clients.push(Arc::new(LspClient::Bolt8 {
+                client: Bolt8LspClient {
+                    node_id: address.0.clone(),
+                    transport: Arc::clone(&transport),
+                },
+                address,
+            }));
+        }
+        for base_url in &config.lsp_urls {
+            clients.push(Arc::new(LspClient::Rest(OlympusLspClient::new(base_url))));
+        }
+        if clients.is_empty() {
+            return Err(anyhow!("No LSP configured"));
+        }
+        Ok(clients)
+    }
+
+    // identifies the LSP in logs
+    fn name(&self) -> &str {
+        match self {
+            LspClient::Rest(client) => &client.base_url,
+            LspClient::Bolt8 { client, .. } => &client.node_id,
+        }
+    }
+
+    // the node address is only known upfront when talking over BOLT8
+    fn node_address(&self) -> Option<(String, String, u16)> {
+        match self {
+            LspClient::Rest(_) => None,
+            LspClient::Bolt8 { address, .. } => Some(address.clone()),
+        }
+    }
+
+    async fn get_info(&self) -> Result<GetInfoResponse> {
+        match self {
+            LspClient::Rest(client) => client.get_info().await,
+            LspClient::Bolt8 { client, .. } => client.get_info().await,
+        }
+    }
+
+    async fn create_order(&self, request: CreateOrderRequest) -> Result<CreateOrderResponse> {
+        match self {
+            LspClient::Rest(client) => client.create_order(request).await,
+            LspClient::Bolt8 { client, .. } => client.create_order(request).await,
+        }
+    }
+
+    async fn get_order(&self, order_id: &str) -> Result<CreateOrderResponse> {
+        match self {
+            LspClient::Rest(client) => client.get_order(order_id).await,
+            LspClient::Bolt8 { client, .. } => client.get_order(order_id).await,
+        }
+    }
+
+    // the REST API wants our public key in the order, over BOLT8 it is implicit
+    fn order_public_key(&self, node_pk: &str) -> Option<String> {
+        match self {
+            LspClient::Rest(_) => Some(node_pk.to_string()),
+            LspClient::Bolt8 { .. } => None,
+        }
+    }
+}
+
+// creates an order and pays it, the returned order is tracked until its channel is open
+async fn buy_channel(
+    client: &LspClient,
+    config: &Config,
     public_key: String,
     ecash_wallet: Arc<Mutex<EcashWallet>>,
-) -> Result<()> {
-    let client = OlympusLspClient::new();
-
+) -> Result<OrderRecord> {
+    let size_sat = config.target_channel_size_sat;
     // Get info
     let info = client.get_info().await?;
     debug!("Info: {:?}", info);
@@ -142,7 +372,8 @@ async fn open_lsp_channel(
         return Err(anyhow!("Requested amount not accepted"));
     }
 
-    // Create order
+    // Create order, with a fresh address of our node to get refunds to
+    let refund_address = new_refund_address().await?;
     let create_order_request = CreateOrderRequest {
         lsp_balance_sat: size_sat.to_string(),
         client_balance_sat: "0".to_string(),
@@ -150,73 +381,686 @@ async fn open_lsp_channel(
         funding_confirms_within_blocks: info.min_funding_confirms_within_blocks,
         channel_expiry_blocks: info.max_channel_expiry_blocks,
         token: "".to_string(),
-        refund_onchain_address: "".to_string(),
+        refund_onchain_address: refund_address.clone(),
         announce_channel: true,
-        public_key,
+        public_key: client.order_public_key(&public_key),
     };
+    let channel_expiry_blocks = create_order_request.channel_expiry_blocks;
     let create_order_response = client.create_order(create_order_request).await?;
     debug!("Create Order Response: {:?}", create_order_response);
-    if ecash_wallet.lock().await.get_total_balance().await?
-        < create_order_response
-            .payment
-            .bolt11
-            .order_total_sat
-            .parse::<u64>()?
-    {
-        return Err(anyhow!("Insufficient balance"));
+    let mut record = OrderRecord {
+        lsp: client.name().to_string(),
+        state: OrderState::Created,
+        order: create_order_response,
+        updated_at: unix_time(),
+        refund_address: Some(refund_address),
+        paid_at: None,
+        refund_received_sat: 0,
+    };
+    record.set_state(OrderState::Created).await?;
+    if let Err(e) = verify_order(
+        &record.order,
+        size_sat,
+        channel_expiry_blocks,
+        config.max_channel_fee_sat,
+        config.max_channel_fee_percent,
+    ) {
+        record.set_state(OrderState::Failed).await?;
+        return Err(e);
+    }
+    pay_order(&mut record, ecash_wallet).await?;
+    Ok(record)
+}
+
+// an order refused for its price or our balance, other LSPs are estimated to be no cheaper
+// so no further orders are placed until the estimates were redone with its real price
+#[derive(Debug)]
+struct OrderTooExpensive(String);
+
+impl std::fmt::Display for OrderTooExpensive {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
+}
 
-    ecash_wallet
-        .lock()
-        .await
-        .pay_lightning_invoice(create_order_response.payment.bolt11.invoice)
-        .await?;
-    tokio::time::sleep(Duration::from_secs(5)).await;
+impl std::error::Error for OrderTooExpensive {}
+
+// the LSP's invoice is paid as is, so the order has to be what we asked for at an acceptable price
+fn verify_order(
+    order: &CreateOrderResponse,
+    size_sat: u64,
+    channel_expiry_blocks: u32,
+    max_fee_sat: Option<u64>,
+    max_fee_percent: f64,
+) -> Result<()> {
+    if order.lsp_balance_sat.parse::<u64>()? != size_sat {
+        return Err(anyhow!(
+            "Order is for a {} sat channel, we asked for {} sat",
+            order.lsp_balance_sat,
+            size_sat
+        ));
+    }
+    if order.channel_expiry_blocks != channel_expiry_blocks {
+        return Err(anyhow!(
+            "Order channel expires after {} blocks, we asked for {}",
+            order.channel_expiry_blocks,
+            channel_expiry_blocks
+        ));
+    }
+
+    let bolt11 = &order.payment.bolt11;
+    let order_total_sat = bolt11.order_total_sat.parse::<u64>()?;
+    let fee_total_sat = bolt11.fee_total_sat.parse::<u64>()?;
+    // we don't ask for a client balance, so all we pay is the fee
+    if order_total_sat != fee_total_sat {
+        return Err(anyhow!(
+            "Order total of {} sat differs from its fee of {} sat",
+            order_total_sat,
+            fee_total_sat
+        ));
+    }
+    if let Some(max_fee_sat) = max_fee_sat {
+        if fee_total_sat > max_fee_sat {
+            return Err(OrderTooExpensive(format!(
+                "Order fee of {} sat exceeds the maximum of {} sat",
+                fee_total_sat, max_fee_sat
+            ))
+            .into());
+        }
+    }
+    // rounded up, a fee of exactly the allowed share passes
+    let max_fee_percent_sat = (size_sat as f64 * max_fee_percent / 100.0).ceil() as u64;
+    if fee_total_sat > max_fee_percent_sat {
+        return Err(OrderTooExpensive(format!(
+            "Order fee of {} sat exceeds {}% of the channel size",
+            fee_total_sat, max_fee_percent
+        ))
+        .into());
+    }
+
+    let invoice = Bolt11Invoice::from_str(&bolt11.invoice)
+        .map_err(|e| anyhow!("Invalid order invoice: {}", e))?;
+    if invoice.amount_milli_satoshis() != Some(order_total_sat * 1000) {
+        return Err(anyhow!(
+            "Order invoice is for {:?} msat, the order total is {} sat",
+            invoice.amount_milli_satoshis(),
+            order_total_sat
+        ));
+    }
+    if invoice.is_expired() {
+        return Err(anyhow!("Order invoice has expired"));
+    }
+    Ok(())
+}
+
+async fn pay_order(record: &mut OrderRecord, ecash_wallet: Arc<Mutex<EcashWallet>>) -> Result<()> {
+    let order_total_sat = record.order.payment.bolt11.order_total_sat.parse::<u64>()?;
+    // checked under the wallet lock, the melt runs without it as it blocks until the
+    // LSP settles its (usually hold) invoice once the channel is open
+    let (payment_wallet, mint_balance_sat) = {
+        let wallet = ecash_wallet.lock().await;
+        if let Err(e) =
+            check_invoice_network(&record.order.payment.bolt11.invoice, wallet.network())
+        {
+            record.set_state(OrderState::Failed).await?;
+            return Err(e);
+        }
+        let balance_sat = wallet.get_total_balance().await?;
+        if balance_sat < order_total_sat {
+            record.set_state(OrderState::Failed).await?;
+            return Err(OrderTooExpensive(format!(
+                "Order costs {} sat, more than our balance of {} sat",
+                order_total_sat, balance_sat
+            ))
+            .into());
+        }
+        wallet.payment_wallet().await?
+    };
+
+    record.set_state(OrderState::Paid).await?;
+    let result = pay_lightning_invoice(
+        &payment_wallet,
+        mint_balance_sat,
+        record.order.payment.bolt11.invoice.clone(),
+        None,
+    )
+    .await;
+    ecash_wallet.lock().await.refresh_balance().await?;
+    match result {
+        Ok(_) => {
+            record.paid_at = Some(unix_time());
+            record.save().await
+        }
+        // nothing left to track, the order would only expire unpaid
+        Err(e) if e.downcast_ref::<InvoiceNotPaid>().is_some() => {
+            record.set_state(OrderState::Failed).await?;
+            Err(e)
+        }
+        // the payment may still be in flight, the LSP tells us what happened
+        Err(e) => {
+            warn!("Error paying LSP order {}: {}", record.order.order_id, e);
+            Ok(())
+        }
+    }
+}
+
+async fn new_refund_address() -> Result<String> {
+    let request = NewaddrRequest {
+        addresstype: Some(NewaddrAddresstype::BECH32),
+    };
+    match send_rpc_request(request.into()).await? {
+        Response::NewAddr(response) => response
+            .bech32
+            .ok_or(anyhow!("newaddr returned no bech32 address")),
+        _ => Err(anyhow!("Unexpected response")),
+    }
+}
+
+// looks for outputs at the refund addresses of failed paid orders
+async fn check_refunds(status: &std::sync::Mutex<ChannelManagerStatus>) -> Result<()> {
+    let records: Vec<OrderRecord> = datastore_list(LSP_ORDERS_NAMESPACE).await?;
+    let mut awaiting_refund = HashMap::new();
+    let mut outputs = None;
+    for mut record in records.into_iter().filter(OrderRecord::awaits_refund) {
+        // spent outputs are included, the refund may have been moved already
+        if outputs.is_none() {
+            let request = ListfundsRequest { spent: Some(true) };
+            outputs = match send_rpc_request(request.into()).await? {
+                Response::ListFunds(response) => Some(response.outputs),
+                _ => return Err(anyhow!("Unexpected response")),
+            };
+        }
+        let mut received_msat = 0;
+        for output in outputs.iter().flatten() {
+            let output = serde_json::to_value(output)?;
+            if output["address"].as_str() == record.refund_address.as_deref() {
+                received_msat += msat_value(&output["amount_msat"]).unwrap_or(0);
+            }
+        }
+        if received_msat == 0 {
+            awaiting_refund.insert(record.order.order_id.clone(), record);
+            continue;
+        }
+        info!(
+            "Refund of {} sat for LSP order {} arrived at {}",
+            received_msat / 1000,
+            record.order.order_id,
+            record.refund_address.as_deref().unwrap_or_default()
+        );
+        record.refund_received_sat = received_msat / 1000;
+        record.save().await?;
+    }
+    status.lock().unwrap().awaiting_refund = awaiting_refund;
+    Ok(())
+}
 
-    // Get order
-    let order_id = &create_order_response.order_id;
-    let get_order_response = client.get_order(order_id).await?;
-    debug!("Get LSP order response: {:?}", get_order_response);
+fn check_invoice_network(invoice: &str, network: &Currency) -> Result<()> {
+    let invoice =
+        Bolt11Invoice::from_str(invoice).map_err(|e| anyhow!("Invalid order invoice: {}", e))?;
+    if invoice.currency() != *network {
+        return Err(anyhow!(
+            "Order invoice is for {:?}, our node is on {:?}",
+            invoice.currency(),
+            network
+        ));
+    }
     Ok(())
 }
 
-pub async fn channel_manager(ecash_wallet: Arc<Mutex<EcashWallet>>) -> Result<()> {
-    let lsp_client = OlympusLspClient::new();
-    let lsp_info = lsp_client.get_info().await?;
-    debug!("LSP Info: {:?}", lsp_info);
-    // create dummy order to get rough estimate of the cost of opening a channel in our configured size
-    let target_channel_size_sat = env::var("TARGET_CHANNEL_SIZE_SAT")
-        .unwrap_or("1000000".to_string())
-        .parse::<u64>()?;
-    let lsp_addr = parse_lsp_host(lsp_info.uris.clone()).pop().unwrap_or((
-        "031b301307574bbe9b9ac7b79cbe1700e31e544513eae0b5d7497483083f99e581".to_string(),
-        "45.79.192.236".to_string(),
-        9735,
-    ));
-    let node_pk = connect_and_get_pk(&lsp_addr.1, lsp_addr.2, &lsp_addr.0).await?;
-    let estimated_cost = lsp_client
-        .get_estimated_cost(target_channel_size_sat, &node_pk)
-        .await?;
-    debug!(
-        "Estimated cost for {} sat channel opening: {}",
-        target_channel_size_sat, estimated_cost
-    );
+// tracks a paid order in the background and reports its final state to the channel manager
+fn spawn_order_tracking(
+    client: Arc<LspClient>,
+    mut record: OrderRecord,
+    state: &PluginState,
+    resolved: mpsc::UnboundedSender<OrderState>,
+) {
+    let status = Arc::clone(&state.channel_status);
+    let mut channel_events = state.channel_events.subscribe();
+    tokio::task::spawn(async move {
+        let order_id = record.order.order_id.clone();
+        status
+            .lock()
+            .unwrap()
+            .in_flight_orders
+            .insert(order_id.clone(), record.clone());
+        let final_state = track_order(&client, &mut record, &status, &mut channel_events).await;
+        status.lock().unwrap().in_flight_orders.remove(&order_id);
+        let _ = resolved.send(final_state);
+    });
+}
+
+// polls the order with backoff until it is completed, failed or refunded, errors are
+// retried as the order only stops counting against max-concurrent-orders once it is final,
+// an order that doesn't end until its tracking deadline is marked failed
+async fn track_order(
+    client: &LspClient,
+    record: &mut OrderRecord,
+    status: &std::sync::Mutex<ChannelManagerStatus>,
+    channel_events: &mut watch::Receiver<()>,
+) -> OrderState {
+    let mut interval = ORDER_POLL_INTERVAL;
+    while !record.state.is_final() {
+        // our channels changing may mean the order's channel is ready
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = channel_events.changed() => {}
+        }
+        if unix_time() > record.tracking_deadline() {
+            warn!(
+                "LSP order {} still {:?} long after its invoice expired, giving up on it",
+                record.order.order_id, record.state
+            );
+            let mut updated = record.clone();
+            match updated.set_state(OrderState::Failed).await {
+                Ok(()) => *record = updated,
+                Err(e) => {
+                    warn!("Error saving LSP order {}: {}", record.order.order_id, e);
+                    interval = (interval * 2).min(MAX_ORDER_POLL_INTERVAL);
+                }
+            }
+            continue;
+        }
+        let order = match client.get_order(&record.order.order_id).await {
+            Ok(order) => order,
+            Err(e) => {
+                warn!("Error polling LSP order {}: {}", record.order.order_id, e);
+                interval = (interval * 2).min(MAX_ORDER_POLL_INTERVAL);
+                continue;
+            }
+        };
+        debug!("Get LSP order response: {:?}", order);
+        let state = match order_state(&order).await {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Error checking LSP order {}: {}", record.order.order_id, e);
+                interval = (interval * 2).min(MAX_ORDER_POLL_INTERVAL);
+                continue;
+            }
+        };
+        // only taken over once saved, a final state must not end the tracking unsaved
+        let mut updated = record.clone();
+        updated.order = order;
+        // a payment we couldn't confirm ourselves counts once the LSP received it
+        if updated.paid_at.is_none()
+            && matches!(updated.order.payment.bolt11.state.as_str(), "HOLD" | "PAID")
+        {
+            updated.paid_at = Some(unix_time());
+        }
+        if let Err(e) = updated.set_state(state).await {
+            warn!("Error saving LSP order {}: {}", record.order.order_id, e);
+            interval = (interval * 2).min(MAX_ORDER_POLL_INTERVAL);
+            continue;
+        }
+        interval = if state == record.state {
+            (interval * 2).min(MAX_ORDER_POLL_INTERVAL)
+        } else {
+            ORDER_POLL_INTERVAL
+        };
+        *record = updated;
+        status
+            .lock()
+            .unwrap()
+            .in_flight_orders
+            .insert(record.order.order_id.clone(), record.clone());
+    }
+    record.state
+}
+
+// our view of a paid order, completed once the channel is usable on our side
+async fn order_state(order: &CreateOrderResponse) -> Result<OrderState> {
+    let bolt11 = &order.payment.bolt11;
+    Ok(match (order.order_state.as_str(), bolt11.state.as_str()) {
+        (_, "REFUNDED") => OrderState::Refunded,
+        ("FAILED", _) => OrderState::Failed,
+        ("COMPLETED", _) if channel_ready(order).await? => OrderState::Completed,
+        ("COMPLETED", _) => OrderState::ChannelPending,
+        // our payment never arrived
+        (_, "EXPECT_PAYMENT")
+            if Bolt11Invoice::from_str(&bolt11.invoice)
+                .map(|invoice| invoice.is_expired())
+                .unwrap_or(false) =>
+        {
+            OrderState::Failed
+        }
+        _ if order.channel.is_some() => OrderState::ChannelPending,
+        _ => OrderState::Paid,
+    })
+}
+
+// the LSP reports the funding outpoint, the channel is ready once it is normal on our side
+async fn channel_ready(order: &CreateOrderResponse) -> Result<bool> {
+    let Some(funding_txid) = order
+        .channel
+        .as_ref()
+        .and_then(|channel| channel["funding_outpoint"].as_str())
+        .and_then(|outpoint| outpoint.split(':').next())
+    else {
+        return Ok(false);
+    };
+    let request = ListpeerchannelsRequest {
+        id: None,
+        short_channel_id: None,
+    };
+    let channels = match send_rpc_request(request.into()).await? {
+        Response::ListPeerChannels(response) => response.channels,
+        _ => return Err(anyhow!("Unexpected response")),
+    };
+    for channel in channels {
+        let channel = serde_json::to_value(channel)?;
+        if channel["funding_txid"] == funding_txid && channel["state"] == "CHANNELD_NORMAL" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// the orders that were in flight when we stopped with their LSPs, orders created but
+// not yet paid are given up as their price may be outdated
+async fn resume_orders(
+    lsp_clients: &[Arc<LspClient>],
+) -> Result<Vec<(Arc<LspClient>, OrderRecord)>> {
+    let records: Vec<OrderRecord> = datastore_list(LSP_ORDERS_NAMESPACE).await?;
+    let mut resumed = Vec::new();
+    for mut record in records
+        .into_iter()
+        .filter(|record| !record.state.is_final())
+    {
+        if record.state == OrderState::Created {
+            record.set_state(OrderState::Failed).await?;
+            continue;
+        }
+        let Some(client) = lsp_clients
+            .iter()
+            .find(|client| client.name() == record.lsp)
+        else {
+            warn!(
+                "LSP {} of order {} is no longer configured, not tracking it",
+                record.lsp, record.order.order_id
+            );
+            continue;
+        };
+        info!(
+            "Resuming LSP order {} at {} ({:?})",
+            record.order.order_id, record.lsp, record.state
+        );
+        resumed.push((Arc::clone(client), record));
+    }
+    Ok(resumed)
+}
+
+// an LSP that accepts our channel size, with the estimated price
+struct LspQuote {
+    client: Arc<LspClient>,
+    info: GetInfoResponse,
+    address: (String, String, u16),
+    estimate: CostEstimate,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CostEstimate {
+    lsp: String,
+    cost_sat: u64,
+    // the order the price was scaled from, None for the default fee model
+    based_on_order: Option<String>,
+}
+
+// scales the fee of the latest order at the LSP to the channel size,
+// orders that were never paid count as well as their price was real
+fn estimate_cost(lsp: &str, size_sat: u64, past_orders: &[OrderRecord]) -> CostEstimate {
+    let latest = past_orders
+        .iter()
+        .filter(|record| record.lsp == lsp)
+        .filter_map(|record| {
+            let order_size_sat = record.order.lsp_balance_sat.parse::<u64>().ok()?;
+            let fee_sat = record
+                .order
+                .payment
+                .bolt11
+                .fee_total_sat
+                .parse::<u64>()
+                .ok()?;
+            (order_size_sat > 0).then_some((record, order_size_sat, fee_sat))
+        })
+        .max_by(|(a, _, _), (b, _, _)| a.order.created_at.cmp(&b.order.created_at));
+    match latest {
+        Some((record, order_size_sat, fee_sat)) => CostEstimate {
+            lsp: lsp.to_string(),
+            cost_sat: (fee_sat as u128 * size_sat as u128).div_ceil(order_size_sat as u128) as u64,
+            based_on_order: Some(record.order.order_id.clone()),
+        },
+        None => CostEstimate {
+            lsp: lsp.to_string(),
+            cost_sat: DEFAULT_FEE_BASE_SAT + size_sat * DEFAULT_FEE_PPM / 1_000_000,
+            based_on_order: None,
+        },
+    }
+}
+
+// returns the LSPs accepting the channel size with their estimated price, cheapest first,
+// without creating any orders
+async fn rank_lsps(
+    lsp_clients: &[Arc<LspClient>],
+    size_sat: u64,
+    network: &Currency,
+) -> Result<Vec<LspQuote>> {
+    let past_orders: Vec<OrderRecord> = datastore_list(LSP_ORDERS_NAMESPACE).await?;
+    let mut quotes = Vec::new();
+    for client in lsp_clients {
+        match quote_lsp(client, size_sat, &past_orders, network).await {
+            Ok(quote) => {
+                debug!(
+                    "LSP {} estimated at {} sat for a {} sat channel",
+                    client.name(),
+                    quote.estimate.cost_sat,
+                    size_sat
+                );
+                quotes.push(quote);
+            }
+            Err(e) => info!("Skipping LSP {}: {}", client.name(), e),
+        }
+    }
+    quotes.sort_by_key(|quote| quote.estimate.cost_sat);
+    Ok(quotes)
+}
+
+async fn quote_lsp(
+    client: &Arc<LspClient>,
+    size_sat: u64,
+    past_orders: &[OrderRecord],
+    network: &Currency,
+) -> Result<LspQuote> {
+    // an LSP on another network is only noticed once it sent an order invoice
+    if let Some(record) = past_orders
+        .iter()
+        .filter(|record| record.lsp == client.name())
+        .max_by(|a, b| a.order.created_at.cmp(&b.order.created_at))
+    {
+        check_invoice_network(&record.order.payment.bolt11.invoice, network)?;
+    }
+    // custom messages can only be exchanged with peers we are connected to
+    if let Some((id, host, port)) = client.node_address() {
+        connect_and_get_pk(&host, port, &id).await?;
+    }
+    let info = client.get_info().await?;
+    debug!("LSP {} info: {:?}", client.name(), info);
+    let min_size = info.min_initial_lsp_balance_sat.parse::<u64>()?;
+    let max_size = info
+        .max_initial_lsp_balance_sat
+        .parse::<u64>()?
+        .min(info.max_channel_balance_sat.parse::<u64>()?);
+    if size_sat < min_size || size_sat > max_size {
+        return Err(anyhow!(
+            "channel size {} sat outside of accepted range {}-{} sat",
+            size_sat,
+            min_size,
+            max_size
+        ));
+    }
+    let address = match client.node_address() {
+        Some(address) => address,
+        None => parse_lsp_host(info.uris.clone()).pop().ok_or(anyhow!(
+            "no reachable clearnet node address in {:?}",
+            info.uris
+        ))?,
+    };
+    Ok(LspQuote {
+        client: Arc::clone(client),
+        info,
+        address,
+        estimate: estimate_cost(client.name(), size_sat, past_orders),
+    })
+}
+
+// buys a channel once the ecash balance covers it, woken up by balance and channel changes,
+// resolved orders and a timer instead of checking continuously
+pub async fn channel_manager(state: PluginState) -> Result<()> {
+    let ecash_wallet = state.wallet.clone();
+    let balance_changed = Arc::clone(&ecash_wallet.lock().await.balance_changed);
+    let mut channel_events = state.channel_events.subscribe();
+    let lsp_clients = LspClient::all_from_config(&state.config(), state.lsps0.clone())?;
+    let (resolved_tx, mut resolved_rx) = mpsc::unbounded_channel();
+    // orders in flight before a restart are tracked again, they are never paid again
+    let mut in_flight_orders = 0;
+    for (client, record) in resume_orders(&lsp_clients).await? {
+        spawn_order_tracking(client, record, &state, resolved_tx.clone());
+        in_flight_orders += 1;
+    }
+    // no purchases before this point, pushed back by every resolved order or failed purchase
+    let mut cooldown_until = tokio::time::Instant::now();
+    // (channel size, estimated cost, time of the estimate), redone when the target size is
+    // changed at runtime, after every purchase and on a schedule
+    let mut estimate: Option<(u64, u64, tokio::time::Instant)> = None;
+    let mut refund_check = tokio::time::interval(REFUND_CHECK_INTERVAL);
     loop {
-        let ecash_balance = ecash_wallet.lock().await.last_balance;
-        trace!("Ecash balance in channel_manager loop: {}", ecash_balance);
+        let config = state.config();
+        if in_flight_orders < config.max_concurrent_orders
+            && tokio::time::Instant::now() >= cooldown_until
+        {
+            match try_buy_channel(&state, &lsp_clients, &mut estimate).await {
+                Ok(Some((client, record))) => {
+                    spawn_order_tracking(client, record, &state, resolved_tx.clone());
+                    in_flight_orders += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Channel purchase failed: {}", e);
+                    cooldown_until = tokio::time::Instant::now() + config.channel_cooldown;
+                }
+            }
+        }
+
+        let wake_at =
+            cooldown_until.max(tokio::time::Instant::now() + config.channel_check_interval);
+        tokio::select! {
+            _ = balance_changed.notified() => trace!("Ecash balance changed"),
+            _ = channel_events.changed() => trace!("Channel state changed"),
+            Some(order_state) = resolved_rx.recv() => {
+                in_flight_orders -= 1;
+                cooldown_until = tokio::time::Instant::now() + config.channel_cooldown;
+                info!(
+                    "LSP order ended {:?}, no purchases for the next {:?}",
+                    order_state, config.channel_cooldown
+                );
+            }
+            _ = refund_check.tick() => {
+                if let Err(e) = check_refunds(&state.channel_status).await {
+                    warn!("Error checking LSP refunds: {}", e);
+                }
+            }
+            _ = tokio::time::sleep_until(wake_at) => {}
+        }
+    }
+}
+
+// buys a channel from the cheapest LSP if the ecash balance covers the estimated cost,
+// returns the paid order
+async fn try_buy_channel(
+    state: &PluginState,
+    lsp_clients: &[Arc<LspClient>],
+    estimate: &mut Option<(u64, u64, tokio::time::Instant)>,
+) -> Result<Option<(Arc<LspClient>, OrderRecord)>> {
+    let config = state.config();
+    let target_channel_size_sat = config.target_channel_size_sat;
+    let network = &config.network;
+    let estimated_cost = match *estimate {
+        Some((size, cost, estimated_at))
+            if size == target_channel_size_sat
+                && estimated_at.elapsed() < ESTIMATE_REFRESH_INTERVAL =>
+        {
+            cost
+        }
+        _ => {
+            let quotes = rank_lsps(lsp_clients, target_channel_size_sat, network).await?;
+            let cheapest = quotes.first().ok_or(anyhow!(
+                "No LSP accepts a {} sat channel",
+                target_channel_size_sat
+            ))?;
+            let cost = cheapest.estimate.cost_sat;
+            debug!(
+                "Estimated cost for {} sat channel opening: {}",
+                target_channel_size_sat, cost
+            );
+            {
+                let mut status = state.channel_status.lock().unwrap();
+                status.lsp = Some(cheapest.client.name().to_string());
+                status.lsp_info = Some(cheapest.info.clone());
+                status.target_channel_size_sat = Some(target_channel_size_sat);
+                status.estimated_cost_sat = Some(cost);
+                status.estimated_at = Some(unix_time());
+                status.estimates = quotes.iter().map(|quote| quote.estimate.clone()).collect();
+            }
+            *estimate = Some((target_channel_size_sat, cost, tokio::time::Instant::now()));
+            cost
+        }
+    };
+    let ecash_balance = state.wallet.lock().await.last_balance;
+    trace!("Ecash balance in channel_manager: {}", ecash_balance);
 
-        // check if balance is enough to open channel
-        if ecash_balance as f64 * 0.9 > estimated_cost as f64 {
-            trace!("Opening LSP channel...");
+    // check if balance is enough to open channel
+    if !balance_covers_cost(ecash_balance, estimated_cost) {
+        return Ok(None);
+    }
+    trace!("Opening LSP channel...");
+    // the orders placed now tell the real prices, estimates are redone with them
+    *estimate = None;
+    for quote in rank_lsps(lsp_clients, target_channel_size_sat, network).await? {
+        info!(
+            "Buying {} sat channel from LSP {}, cheapest estimate at {} sat",
+            target_channel_size_sat,
+            quote.client.name(),
+            quote.estimate.cost_sat
+        );
+        let result = async {
             // connect to LSP node and get our public key
-            let node_pk = connect_and_get_pk(&lsp_addr.1, lsp_addr.2, &lsp_addr.0).await?;
-            open_lsp_channel(target_channel_size_sat, node_pk, ecash_wallet.clone()).await?;
-            tokio::time::sleep(Duration::from_secs(15)).await;
+            let (id, host, port) = &quote.address;
+            let node_pk = connect_and_get_pk(host, *port, id).await?;
+            buy_channel(&quote.client, &config, node_pk, state.wallet.clone()).await
+        };
+        match result.await {
+            Ok(record) => return Ok(Some((quote.client, record))),
+            // the next LSP would most likely be refused as well, after creating another order
+            Err(e) if e.downcast_ref::<OrderTooExpensive>().is_some() => return Err(e),
+            Err(e) => warn!(
+                "Order at LSP {} failed, trying next one: {}",
+                quote.client.name(),
+                e
+            ),
         }
     }
+    Err(anyhow!("No LSP order could be placed"))
 }
 
-fn parse_lsp_host(addresses: Vec<String>) -> Vec<(String, String, u16)> {
+// channel state changes can complete in-flight orders or make a purchase worthwhile again
+pub async fn channel_state_changed_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    trace!("Channel state changed: {}", v);
+    p.state().channel_events.send_replace(());
+    Ok(())
+}
+
+pub fn parse_lsp_host(addresses: Vec<String>) -> Vec<(String, String, u16)> {
     addresses
         .into_iter()
         .filter_map(|address| {
@@ -244,9 +1088,148 @@ fn parse_lsp_host(addresses: Vec<String>) -> Vec<(String, String, u16)> {
         .collect()
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cln_liquidity_plugin::tests::{signed_invoice, signed_invoice_at};
+
+    const SIZE_SAT: u64 = 1_000_000;
+    const EXPIRY_BLOCKS: u32 = 13_000;
+
+    // an order for a SIZE_SAT channel at the given fee, paid by an invoice for invoice_sat
+    fn order(fee_sat: u64, invoice_sat: u64, invoice_expiry_secs: u64) -> CreateOrderResponse {
+        CreateOrderResponse {
+            announce_channel: true,
+            channel: None,
+            channel_expiry_blocks: EXPIRY_BLOCKS,
+            client_balance_sat: "0".to_string(),
+            funding_confirms_within_blocks: 6,
+            created_at: "2024-10-18T12:00:00.000Z".to_string(),
+            lsp_balance_sat: SIZE_SAT.to_string(),
+            order_id: "order".to_string(),
+            order_state: "CREATED".to_string(),
+            payment: Payment {
+                bolt11: Bolt11 {
+                    order_total_sat: fee_sat.to_string(),
+                    fee_total_sat: fee_sat.to_string(),
+                    invoice: signed_invoice(
+                        Currency::Bitcoin,
+                        invoice_sat * 1000,
+                        invoice_expiry_secs,
+                        "order",
+                    ),
+                    state: "EXPECT_PAYMENT".to_string(),
+                    expires_at: "2024-10-18T13:00:00.000Z".to_string(),
+                },
+            },
+            token: String::new(),
+        }
+    }
+
+    fn verify(
+        order: &CreateOrderResponse,
+        max_fee_sat: Option<u64>,
+        max_fee_percent: f64,
+    ) -> Result<()> {
+        verify_order(order, SIZE_SAT, EXPIRY_BLOCKS, max_fee_sat, max_fee_percent)
+    }
+
+    fn too_expensive(result: Result<()>) -> bool {
+        result.is_err_and(|e| e.downcast_ref::<OrderTooExpensive>().is_some())
+    }
 
     #[tokio::test]
     async fn test_lsp_interface() {}
+
+    #[test]
+    fn accepts_order_as_requested() {
+        assert!(verify(&order(10_000, 10_000, 3600), Some(10_000), 1.0).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_channel_size() {
+        let mut order = order(10_000, 10_000, 3600);
+        order.lsp_balance_sat = (SIZE_SAT / 2).to_string();
+        assert!(verify(&order, None, 10.0).is_err());
+    }
+
+    #[test]
+    fn rejects_other_channel_expiry() {
+        let mut order = order(10_000, 10_000, 3600);
+        order.channel_expiry_blocks = EXPIRY_BLOCKS / 2;
+        assert!(verify(&order, None, 10.0).is_err());
+    }
+
+    #[test]
+    fn rejects_order_total_other_than_fee() {
+        let mut order = order(10_000, 20_000, 3600);
+        order.payment.bolt11.order_total_sat = "20000".to_string();
+        let result = verify(&order, None, 10.0);
+        assert!(result.is_err() && !too_expensive(result));
+    }
+
+    #[test]
+    fn rejects_fee_above_absolute_cap() {
+        assert!(too_expensive(verify(
+            &order(10_001, 10_001, 3600),
+            Some(10_000),
+            10.0
+        )));
+    }
+
+    #[test]
+    fn rejects_fee_above_percent_cap() {
+        assert!(too_expensive(verify(
+            &order(10_001, 10_001, 3600),
+            None,
+            1.0
+        )));
+        // 1.5% of 1000001 sat are 15000.015 sat, a fee of 15001 sat is within the cap
+        let size_sat = SIZE_SAT + 1;
+        let mut within = order(15_001, 15_001, 3600);
+        within.lsp_balance_sat = size_sat.to_string();
+        assert!(verify_order(&within, size_sat, EXPIRY_BLOCKS, None, 1.5).is_ok());
+        let mut above = order(15_002, 15_002, 3600);
+        above.lsp_balance_sat = size_sat.to_string();
+        assert!(too_expensive(verify_order(
+            &above,
+            size_sat,
+            EXPIRY_BLOCKS,
+            None,
+            1.5
+        )));
+    }
+
+    #[test]
+    fn rejects_invoice_for_other_amount() {
+        let result = verify(&order(10_000, 10_001, 3600), None, 10.0);
+        assert!(result.is_err() && !too_expensive(result));
+    }
+
+    #[test]
+    fn rejects_expired_invoice() {
+        let mut order = order(10_000, 10_000, 3600);
+        order.payment.bolt11.invoice = signed_invoice_at(
+            Currency::Bitcoin,
+            10_000_000,
+            unix_time() - 7200,
+            3600,
+            "order",
+        );
+        assert!(verify(&order, None, 10.0).is_err());
+    }
+
+    #[test]
+    fn sats_needed_triggers_the_purchase() {
+        for cost in [0, 1, 9, 900, 9_000, 10_000, 12_345] {
+            let status = ChannelManagerStatus {
+                estimated_cost_sat: Some(cost),
+                ..Default::default()
+            };
+            let required = status.sats_needed(0).unwrap();
+            assert!(balance_covers_cost(required, cost));
+            assert!(required == 0 || !balance_covers_cost(required - 1, cost));
+            assert_eq!(status.sats_needed(required), Some(0));
+        }
+    }
 }
@@ -0,0 +1,199 @@
+use super::*;
+
+// LSPS0 JSON-RPC messages are wrapped in BOLT8 custom messages of this (odd) type
+const LSPS0_MESSAGE_TYPE: u16 = 37913;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize)]
+struct JsonRpcRequest<'a, P: Serialize> {
+    jsonrpc: &'a str,
+    method: &'a str,
+    params: P,
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcResponse {
+    id: String,
+    result: Option<serde_json::Value>,
+    error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CustomMsgHookCall {
+    peer_id: String,
+    payload: String,
+}
+
+// request id -> (peer id, channel to the waiting caller)
+type PendingRequests = HashMap<String, (String, oneshot::Sender<JsonRpcResponse>)>;
+
+// sends LSPS0 requests to peers via sendcustommsg and matches the replies
+// coming in through the custommsg hook to the waiting callers
+#[derive(Default)]
+pub struct Lsps0Transport {
+    pending_requests: std::sync::Mutex<PendingRequests>,
+}
+
+impl Lsps0Transport {
+    pub async fn call<P: Serialize, R: DeserializeOwned>(
+        &self,
+        peer_id: &str,
+        method: &str,
+        params: P,
+    ) -> Result<R> {
+        let id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+        let request = JsonRpcRequest {
+            jsonrpc: "2.0",
+            method,
+            params,
+            id: id.clone(),
+        };
+        let mut message = LSPS0_MESSAGE_TYPE.to_be_bytes().to_vec();
+        message.extend(serde_json::to_vec(&request)?);
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending_requests
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (peer_id.to_string(), sender));
+
+        let request = SendcustommsgRequest {
+            node_id: PublicKey::from_str(peer_id)?,
+            msg: hex::encode(message),
+        };
+        if let Err(e) = send_rpc_request(request.into()).await {
+            self.pending_requests.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        trace!("Sent LSPS0 request {} ({}) to {}", method, id, peer_id);
+
+        let response = match tokio::time::timeout(RESPONSE_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => response,
+            _ => {
+                self.pending_requests.lock().unwrap().remove(&id);
+                return Err(anyhow!("No response from {} to {}", peer_id, method));
+            }
+        };
+        if let Some(error) = response.error {
+            return Err(anyhow!(
+                "LSP returned error {} on {}: {} ({:?})",
+                error.code,
+                method,
+                error.message,
+                error.data
+            ));
+        }
+        Ok(serde_json::from_value(response.result.ok_or(anyhow!(
+            "LSP response to {} contains neither result nor error",
+            method
+        ))?)?)
+    }
+
+    // returns true if the message was an answer to one of our requests
+    fn handle_message(&self, peer_id: &str, payload: &[u8]) -> bool {
+        if payload.len() < 2 || payload[..2] != LSPS0_MESSAGE_TYPE.to_be_bytes() {
+            return false;
+        }
+        let response: JsonRpcResponse = match serde_json::from_slice(&payload[2..]) {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Received invalid LSPS0 message from {}: {}", peer_id, e);
+                return false;
+            }
+        };
+        let mut pending_requests = self.pending_requests.lock().unwrap();
+        // only accept the answer from the peer we sent the request to
+        match pending_requests.get(&response.id) {
+            Some((expected_peer, _)) if expected_peer == peer_id => {}
+            _ => {
+                debug!(
+                    "Unsolicited LSPS0 message from {}: {}",
+                    peer_id, response.id
+                );
+                return false;
+            }
+        }
+        let (_, sender) = pending_requests.remove(&response.id).unwrap();
+        sender.send(response).is_ok()
+    }
+}
+
+// handler for the custommsg hook, picks out LSPS0 replies and lets everything else pass
+pub async fn custommsg_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    if let Ok(call) = serde_json::from_value::<CustomMsgHookCall>(v) {
+        if let Ok(payload) = hex::decode(&call.payload) {
+            p.state().lsps0.handle_message(&call.peer_id, &payload);
+        }
+    }
+    Ok(json!({"result": "continue"}))
+}
+
+// LSPS0 datetimes are ISO 8601 UTC strings like 2023-02-23T08:47:30.511Z, returned as unix time
+pub fn parse_datetime(value: &str) -> Result<u64> {
+    let invalid = || anyhow!("Invalid LSPS0 datetime {}", value);
+    let (date, time) = value
+        .strip_suffix('Z')
+        .and_then(|value| value.split_once('T'))
+        .ok_or_else(invalid)?;
+    // fractions of a second don't matter to us
+    let time = time.split('.').next().unwrap_or_default();
+    let parse = |part: &str, parts: usize| -> Result<Vec<i64>> {
+        let numbers = part
+            .split(['-', ':'])
+            .map(|number| number.parse::<i64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>>>()?;
+        (numbers.len() == parts)
+            .then_some(numbers)
+            .ok_or_else(invalid)
+    };
+    let (date, time) = (parse(date, 3)?, parse(time, 3)?);
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || !(0..24).contains(&time[0])
+        || !(0..60).contains(&time[1])
+        || !(0..=60).contains(&time[2])
+    {
+        return Err(invalid());
+    }
+    // days since the epoch of the proleptic gregorian calendar, years starting in march
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    u64::try_from(days * 86_400 + time[0] * 3600 + time[1] * 60 + time[2]).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_datetimes() {
+        assert_eq!(parse_datetime("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(
+            parse_datetime("2023-02-23T08:47:30.511Z").unwrap(),
+            1_677_142_050
+        );
+        assert_eq!(
+            parse_datetime("2024-02-29T23:59:59Z").unwrap(),
+            1_709_251_199
+        );
+        assert!(parse_datetime("2023-02-23T08:47:30").is_err());
+        assert!(parse_datetime("2023-13-01T00:00:00Z").is_err());
+        assert!(parse_datetime("1969-12-31T23:59:59Z").is_err());
+    }
+}
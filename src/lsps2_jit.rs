@@ -0,0 +1,389 @@
+use super::*;
+
+// LSPS2 just-in-time channels: the LSP gives us an intercept SCID to put in the
+// route hint of our invoice and opens the channel once the payment arrives,
+// deducting its opening fee from the forwarded amount
+
+const MIN_FINAL_CLTV_EXPIRY_DELTA: u64 = 18;
+// how long we hold already arrived parts of a multi part payment
+const MPP_TIMEOUT: Duration = Duration::from_secs(90);
+// datastore namespace of the JIT payments we handed out invoices for, keyed by payment hash
+const JIT_PAYMENTS_NAMESPACE: &str = "jit_payments";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OpeningFeeParams {
+    min_fee_msat: String,
+    proportional: u64,
+    valid_until: String,
+    min_lifetime: u32,
+    max_client_to_self_delay: u32,
+    min_payment_size_msat: String,
+    max_payment_size_msat: String,
+    promise: String,
+}
+
+impl OpeningFeeParams {
+    // opening fee for a payment of the given size, None if the LSP doesn't accept that size
+    fn opening_fee_msat(&self, payment_size_msat: u64) -> Option<u64> {
+        let min_payment = self.min_payment_size_msat.parse::<u64>().ok()?;
+        let max_payment = self.max_payment_size_msat.parse::<u64>().ok()?;
+        if payment_size_msat < min_payment || payment_size_msat > max_payment {
+            return None;
+        }
+        // rounded up as specified in LSPS2
+        let proportional_fee =
+            (payment_size_msat as u128 * self.proportional as u128).div_ceil(1_000_000) as u64;
+        let fee = proportional_fee.max(self.min_fee_msat.parse::<u64>().ok()?);
+        // the payment has to cover the fee
+        (fee < payment_size_msat).then_some(fee)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetInfoResponse {
+    opening_fee_params_menu: Vec<OpeningFeeParams>,
+}
+
+#[derive(Debug, Serialize)]
+struct BuyRequest {
+    opening_fee_params: OpeningFeeParams,
+    payment_size_msat: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BuyResponse {
+    jit_channel_scid: String,
+    lsp_cltv_expiry_delta: u16,
+    #[serde(default)]
+    client_trusts_lsp: bool,
+}
+
+// what is kept of a pending JIT payment across restarts
+#[derive(Debug, Serialize, Deserialize)]
+struct JitPaymentRecord {
+    payment_hash: String,
+    preimage: String,
+    expected_msat: u64,
+    expires_at: u64,
+}
+
+// a sold JIT channel waiting for the payment to be forwarded through it
+struct PendingJitPayment {
+    preimage: String,
+    // what the LSP forwards to us after deducting its fee
+    expected_msat: u64,
+    expires_at: u64,
+    received_msat: u64,
+    settled: watch::Sender<bool>,
+}
+
+pub struct Lsps2Client {
+    node_id: String,
+    address: (String, String, u16),
+    transport: Arc<Lsps0Transport>,
+    // payment hash -> payment we are waiting for
+    pending_payments: std::sync::Mutex<HashMap<String, PendingJitPayment>>,
+    // set once the payments of a previous run are reloaded, HTLCs wait for it
+    payments_loaded: watch::Sender<bool>,
+    // of our node, the invoices are issued for it
+    network: Currency,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FallbackMode {
+    Ecash,
+    Jit,
+    // JIT channels for payments of at least `jit_min_amount_sat`, ecash below
+    Hybrid,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FallbackPolicy {
+    pub mode: FallbackMode,
+    pub jit_min_amount_sat: u64,
+}
+
+impl FromStr for FallbackMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "ecash" => Ok(FallbackMode::Ecash),
+            "jit" => Ok(FallbackMode::Jit),
+            "hybrid" => Ok(FallbackMode::Hybrid),
+            other => Err(anyhow!("Invalid fallback mode: {}", other)),
+        }
+    }
+}
+
+impl FallbackPolicy {
+    pub fn use_jit(&self, amount_msat: u64) -> bool {
+        match self.mode {
+            FallbackMode::Ecash => false,
+            FallbackMode::Jit => true,
+            FallbackMode::Hybrid => amount_msat >= self.jit_min_amount_sat.saturating_mul(1000),
+        }
+    }
+}
+
+impl Lsps2Client {
+    // node_uri is id@host:port
+    pub fn new(node_uri: &str, transport: Arc<Lsps0Transport>, network: Currency) -> Result<Self> {
+        let address = parse_lsp_host(vec![node_uri.to_string()])
+            .pop()
+            .ok_or(anyhow!("Invalid LSPS2 node: {}", node_uri))?;
+        Ok(Lsps2Client {
+            node_id: address.0.clone(),
+            address,
+            transport,
+            pending_payments: std::sync::Mutex::new(HashMap::new()),
+            payments_loaded: watch::Sender::new(false),
+            network,
+        })
+    }
+
+    // buys a JIT channel for this payment and returns an invoice routing over it,
+    // preimage, cltv and deschashonly of the invoice call are honored
+    pub async fn create_jit_invoice(
+        &self,
+        amount_msat: u64,
+        params: &InvoiceParams,
+        expiry_secs: u64,
+    ) -> Result<String> {
+        // custom messages need a connection to the LSP
+        let (id, host, port) = &self.address;
+        connect_and_get_pk(host, *port, id).await?;
+
+        let info: GetInfoResponse = self
+            .transport
+            .call(&self.node_id, "lsps2.get_info", json!({}))
+            .await?;
+        let now = unix_time();
+        let (opening_fee_params, opening_fee_msat, valid_until) = info
+            .opening_fee_params_menu
+            .into_iter()
+            .filter_map(|params| {
+                // the LSP refuses to sell at expired params
+                let valid_until = parse_datetime(&params.valid_until).ok()?;
+                if valid_until <= now {
+                    return None;
+                }
+                let fee = params.opening_fee_msat(amount_msat)?;
+                Some((params, fee, valid_until))
+            })
+            .min_by_key(|(_, fee, _)| *fee)
+            .ok_or(anyhow!(
+                "LSP offers no valid JIT channel for {} msat payments",
+                amount_msat
+            ))?;
+        // the invoice must not outlive the fee params the channel is bought at
+        let expiry_secs = expiry_secs.min(valid_until - now);
+        debug!(
+            "Buying JIT channel for {} msat payment, opening fee: {} msat",
+            amount_msat, opening_fee_msat
+        );
+        let buy_response: BuyResponse = self
+            .transport
+            .call(
+                &self.node_id,
+                "lsps2.buy",
+                BuyRequest {
+                    opening_fee_params,
+                    payment_size_msat: amount_msat.to_string(),
+                },
+            )
+            .await?;
+        debug!("LSPS2 buy response: {:?}", buy_response);
+
+        let preimage = params
+            .preimage
+            .unwrap_or_else(|| rand::thread_rng().gen::<[u8; 32]>());
+        let payment_secret = rand::thread_rng().gen::<[u8; 32]>();
+        let payment_hash = sha256::Hash::hash(&preimage);
+        let route_hint = RouteHint(vec![RouteHintHop {
+            src_node_id: secp256k1::PublicKey::from_str(&self.node_id)?,
+            short_channel_id: parse_short_channel_id(&buy_response.jit_channel_scid)?,
+            fees: RoutingFees {
+                base_msat: 0,
+                proportional_millionths: 0,
+            },
+            cltv_expiry_delta: buy_response.lsp_cltv_expiry_delta,
+            htlc_minimum_msat: None,
+            htlc_maximum_msat: None,
+        }]);
+        let description = Description::new(params.description.clone())?;
+        let description_hash = Sha256(sha256::Hash::hash(params.description.as_bytes()));
+        let invoice_description = if params.deschashonly {
+            Bolt11InvoiceDescription::Hash(&description_hash)
+        } else {
+            Bolt11InvoiceDescription::Direct(&description)
+        };
+        // signed with a throwaway key, signinvoice replaces the signature with our node's
+        let throwaway_key =
+            secp256k1::SecretKey::from_slice(&rand::thread_rng().gen::<[u8; 32]>())?;
+        let unsigned_invoice = InvoiceBuilder::new(self.network.clone())
+            .invoice_description(invoice_description)
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(payment_secret))
+            .amount_milli_satoshis(amount_msat)
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(params.cltv.map_or(MIN_FINAL_CLTV_EXPIRY_DELTA, u64::from))
+            .expiry_time(Duration::from_secs(expiry_secs))
+            .private_route(route_hint)
+            .basic_mpp()
+            .build_signed(|hash| {
+                secp256k1::Secp256k1::new().sign_ecdsa_recoverable(hash, &throwaway_key)
+            })?;
+        let response = send_rpc_request(
+            SigninvoiceRequest {
+                invstring: unsigned_invoice.to_string(),
+            }
+            .into(),
+        )
+        .await?;
+        let bolt11 = match response {
+            Response::SignInvoice(response) => response.bolt11,
+            _ => return Err(anyhow!("Unexpected response")),
+        };
+
+        let record = JitPaymentRecord {
+            payment_hash: payment_hash.to_string(),
+            preimage: hex::encode(preimage),
+            expected_msat: amount_msat - opening_fee_msat,
+            expires_at: unix_time() + expiry_secs,
+        };
+        // persisted before the invoice is handed out, the LSP forwards the payment even if
+        // we restarted in the meantime
+        datastore_put(JIT_PAYMENTS_NAMESPACE, &record.payment_hash, &record).await?;
+        self.track_payment(record);
+        Ok(bolt11)
+    }
+
+    fn track_payment(&self, record: JitPaymentRecord) {
+        self.pending_payments.lock().unwrap().insert(
+            record.payment_hash,
+            PendingJitPayment {
+                preimage: record.preimage,
+                expected_msat: record.expected_msat,
+                expires_at: record.expires_at,
+                received_msat: 0,
+                settled: watch::Sender::new(false),
+            },
+        );
+    }
+
+    // reloads the payments of JIT invoices handed out before a restart, expired ones are deleted
+    pub async fn load_pending_payments(&self) -> Result<()> {
+        let result = async {
+            let records: Vec<JitPaymentRecord> = datastore_list(JIT_PAYMENTS_NAMESPACE).await?;
+            for record in records {
+                if record.expires_at <= unix_time() {
+                    forget_payment(&record.payment_hash).await;
+                    continue;
+                }
+                info!("Reloaded pending JIT payment {}", record.payment_hash);
+                self.track_payment(record);
+            }
+            Ok::<_, Error>(())
+        }
+        .await;
+        // HTLCs replayed at startup are handled with whatever could be loaded
+        self.payments_loaded.send_replace(true);
+        result
+    }
+
+    // adds an incoming HTLC to its JIT payment, returns the preimage and the amount received
+    // once the whole payment arrived, None if the HTLC doesn't belong to a JIT invoice
+    async fn settle_htlc(
+        &self,
+        payment_hash: &str,
+        amount_msat: u64,
+    ) -> Option<Result<(String, u64)>> {
+        let _ = self
+            .payments_loaded
+            .subscribe()
+            .wait_for(|loaded| *loaded)
+            .await;
+        let mut settled = {
+            let mut pending_payments = self.pending_payments.lock().unwrap();
+            pending_payments.retain(|_, payment| payment.expires_at > unix_time());
+            let payment = pending_payments.get_mut(payment_hash)?;
+            payment.received_msat += amount_msat;
+            if payment.received_msat >= payment.expected_msat {
+                // releases the parts that arrived before
+                payment.settled.send_replace(true);
+                return Some(Ok((payment.preimage.clone(), payment.received_msat)));
+            }
+            payment.settled.subscribe()
+        };
+        // hold this part until the rest of the payment arrived
+        match tokio::time::timeout(MPP_TIMEOUT, settled.wait_for(|settled| *settled)).await {
+            Ok(Ok(_)) => self
+                .pending_payments
+                .lock()
+                .unwrap()
+                .get(payment_hash)
+                .map(|payment| Ok((payment.preimage.clone(), payment.received_msat))),
+            _ => {
+                self.pending_payments.lock().unwrap().remove(payment_hash);
+                Some(Err(anyhow!("JIT payment {} incomplete", payment_hash)))
+            }
+        }
+    }
+}
+
+// resolves HTLCs coming in over a JIT channel with the preimage of our invoice,
+// CLN doesn't know these invoices and couldn't accept the amount reduced by the opening fee
+pub async fn htlc_accepted_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let lsps2 = match &p.state().lsps2 {
+        Some(lsps2) => Arc::clone(lsps2),
+        None => return Ok(json!({"result": "continue"})),
+    };
+    let payment_hash = v["htlc"]["payment_hash"].as_str().unwrap_or_default();
+    let amount_msat = msat_value(&v["htlc"]["amount_msat"]).unwrap_or(0);
+    match lsps2.settle_htlc(payment_hash, amount_msat).await {
+        Some(Ok((preimage, received_msat))) => {
+            info!("Received JIT channel payment {}", payment_hash);
+            // every part of the payment gets here, the record is only updated once
+            if p.state()
+                .invoices
+                .mark_hash_paid(payment_hash, received_msat)
+                .await
+                .is_some()
+            {
+                forget_payment(payment_hash).await;
+            }
+            Ok(json!({"result": "resolve", "payment_key": preimage}))
+        }
+        Some(Err(e)) => {
+            warn!("Failing JIT channel HTLC: {}", e);
+            // incorrect_or_unknown_payment_details
+            Ok(json!({"result": "fail", "failure_message": "400f"}))
+        }
+        None => Ok(json!({"result": "continue"})),
+    }
+}
+
+async fn forget_payment(payment_hash: &str) {
+    if let Err(e) = datastore_delete(JIT_PAYMENTS_NAMESPACE, payment_hash).await {
+        warn!(
+            "Error deleting JIT payment {} from datastore: {}",
+            payment_hash, e
+        );
+    }
+}
+
+// short channel ids are passed as BLOCKxTXxOUTPUT strings
+fn parse_short_channel_id(scid: &str) -> Result<u64> {
+    let parts: Vec<u64> = scid
+        .split('x')
+        .map(|part| part.parse::<u64>())
+        .collect::<Result<_, _>>()?;
+    match parts[..] {
+        [block, tx, output] => Ok(block << 40 | tx << 16 | output),
+        _ => Err(anyhow!("Invalid short channel id: {}", scid)),
+    }
+}
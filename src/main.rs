@@ -1,40 +1,131 @@
 mod cln_liquidity_plugin;
+mod config;
+mod datastore;
 mod ecash_wallet;
+mod invoice_params;
+mod invoice_store;
+mod liquidity_policy;
 mod lsp_channel_opener;
+mod lsps0_transport;
+mod lsps2_jit;
+mod mint_subscription;
+mod rpc_client;
+mod rpc_methods;
 
 use anyhow::{anyhow, Error, Result};
+use bitcoin::{
+    hashes::{sha256, Hash},
+    secp256k1,
+};
 use cdk::{
     amount::{Amount, SplitTarget},
     error,
-    nuts::{CurrencyUnit, MeltQuoteState},
-    wallet::Wallet,
+    nuts::{
+        CurrencyUnit, MeltQuoteState, MintQuoteState, PublicKey as CashuPublicKey,
+        SpendingConditions, Token,
+    },
+    wallet::{SendKind, Wallet},
+};
+use cdk_redb::WalletRedbDatabase;
+use cln_liquidity_plugin::{
+    connect_and_get_pk, get_receivable_capacity, msat_value, rpc_command_handler,
+};
+use cln_plugin::{
+    messages,
+    options::{
+        self, BooleanConfigOption, ConfigOption, IntegerConfigOption, StringArrayConfigOption,
+        StringConfigOption,
+    },
+    Builder, ConfiguredPlugin, Plugin,
 };
-use cln_liquidity_plugin::{connect_and_get_pk, rpc_command_handler};
-use cln_plugin::{Builder, Plugin};
 use cln_rpc::{
     model::{
-        requests::{ConnectRequest, GetinfoRequest, ListfundsRequest},
+        requests::{
+            ConnectRequest, DatastoreMode, DatastoreRequest, DeldatastoreRequest,
+            DelinvoiceRequest, DelinvoiceStatus, GetinfoRequest, InvoiceRequest,
+            ListdatastoreRequest, ListfundsRequest, ListinvoicesIndex, ListinvoicesRequest,
+            ListpeerchannelsRequest, NewaddrAddresstype, NewaddrRequest, SendcustommsgRequest,
+            SigninvoiceRequest, WaitIndexname, WaitRequest, WaitSubsystem, WaitanyinvoiceRequest,
+        },
         Request, Response,
     },
-    ClnRpc,
+    primitives::{AmountOrAny, ChannelState, PublicKey},
+    ClnRpc, RpcError,
+};
+use config::{node_network, setconfig_handler, Config, MintConfig, OPT_LSP_NODE};
+use datastore::{
+    datastore_delete, datastore_get, datastore_increment, datastore_list, datastore_put,
+    COUNTERS_NAMESPACE,
 };
 use dotenvy::dotenv;
-use ecash_wallet::{mint_pending_mint_requests, EcashWallet};
+use ecash_wallet::{
+    mint_pending_mint_requests, pay_lightning_invoice, unix_time, EcashWallet, InvoiceNotPaid,
+    PaymentRequest,
+};
 use env_logger::Target;
+use futures_util::{SinkExt, StreamExt};
+use invoice_params::{named_params, ExposePrivateChannels, InvoiceParams};
+use invoice_store::{
+    error_response, invoice_rpc_handler, notify_invoice_paid, InvoiceRecord, InvoiceStore,
+};
+use lightning_invoice::{
+    Bolt11Invoice, Bolt11InvoiceDescription, Currency, Description, InvoiceBuilder, PaymentSecret,
+    RouteHint, RouteHintHop, RoutingFees, Sha256,
+};
+use liquidity_policy::{
+    FallbackDecision, FallbackOverride, FallbackRequest, LiquidityPolicy, ThresholdPolicy,
+    FALLBACK_OVERRIDE_PARAM,
+};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use lsp_channel_opener::channel_manager;
-// use lsp_channel_opener::channel_manager;
+use lsp_channel_opener::{
+    channel_manager, channel_state_changed_handler, parse_lsp_host, ChannelManagerStatus,
+};
+use lsps0_transport::{custommsg_handler, parse_datetime, Lsps0Transport};
+use lsps2_jit::{htlc_accepted_handler, FallbackMode, FallbackPolicy, Lsps2Client};
+use mint_subscription::MintQuoteWatcher;
 use rand::Rng;
-use serde::{Deserialize, Serialize};
+use rpc_client::{init_rpc_client, send_rpc_request};
+use rpc_methods::{pay_handler, receive_handler, send_handler, status_handler};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::json;
 use std::{
-    env, fs::OpenOptions, io::Write, path::Path, sync::Arc, thread::AccessError, time::Duration,
+    collections::HashMap,
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, OnceLock, RwLock},
+    thread::AccessError,
+    time::Duration,
 };
 use tokio::{
-    io::{stdin as tokio_stdin, stdout as tokio_stdout, AsyncBufReadExt},
-    sync::Mutex,
+    io::{stdin as tokio_stdin, stdout as tokio_stdout, AsyncBufReadExt, Stdin, Stdout},
+    sync::{mpsc, oneshot, watch, Mutex, Notify},
 };
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+// state shared between the plugin hooks and the background tasks
+#[derive(Clone)]
+pub struct PluginState {
+    pub wallet: Arc<Mutex<EcashWallet>>,
+    pub lsps0: Arc<Lsps0Transport>,
+    pub lsps2: Option<Arc<Lsps2Client>>,
+    pub config: Arc<RwLock<Config>>,
+    pub channel_status: Arc<std::sync::Mutex<ChannelManagerStatus>>,
+    pub invoices: Arc<InvoiceStore>,
+    // bumped on every channel_state_changed notification
+    pub channel_events: Arc<watch::Sender<()>>,
+}
+
+impl PluginState {
+    // snapshot of the current config, options can change at runtime via setconfig
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+}
 
 // disclaimer: started hacking on this on Thursday (some research, ecash functions and part of the readme)
 #[tokio::main]
@@ -45,42 +136,145 @@ async fn main() -> anyhow::Result<()> {
         .filter_module("kickstart_cln", log::LevelFilter::Trace)
         .target(Target::Stderr)
         .init();
-    // load .env file
+    // load .env file (deprecated, use the plugin options instead)
     dotenv().ok();
 
     warn!("This is a hackathon project, usage is definitely reckless!");
 
-    // initialize ecash wallet
-    let wallet = Arc::new(Mutex::new(EcashWallet::new().await?));
-    let minting_wallet = Arc::clone(&wallet);
-    let channel_manager_wallet = Arc::clone(&wallet);
-    tokio::task::spawn(async move { mint_pending_mint_requests(minting_wallet).await });
-
-    tokio::task::spawn(async move {
-        let err = channel_manager(channel_manager_wallet).await;
-        error!("Channel manager exited: {:?}", err);
-        tokio::time::sleep(Duration::from_secs(60)).await;
-    });
-
-    // run ecash wallet demo
-    // _demo(&mut *wallet.lock().await).await?;
-
     // catch created invoice // hook @ lightning-invoice
     // check inbound liquidity // lightning-listchannels RPC
     // if inbound liquidity is low, replace invoice with cashu invoice
     // check if balance is enough to open channel
     trace!("Starting cln plugin...");
-    let state = wallet;
-    if let Some(plugin) = Builder::new(tokio_stdin(), tokio_stdout())
+    let configured_plugin = match Builder::new(tokio_stdin(), tokio_stdout())
+        .option(config::OPT_MINT_URL.dynamic())
+        .option(config::OPT_MINT_MAX_BALANCE_SAT)
+        .option(config::OPT_LSP_NODE)
+        .option(config::OPT_LSP_URL)
+        .option(config::OPT_LSPS2_NODE)
+        .option(config::OPT_TARGET_CHANNEL_SIZE_SAT.dynamic())
+        .option(config::OPT_LIQUIDITY_BUFFER_FACTOR.dynamic())
+        .option(config::OPT_LIQUIDITY_MARGIN_SAT)
+        .option(config::OPT_FALLBACK_MIN_AMOUNT_SAT)
+        .option(config::OPT_FALLBACK_MAX_AMOUNT_SAT)
+        .option(config::OPT_FALLBACK_OPT_OUT_LABEL_PREFIX)
+        .option(config::OPT_MINT_POLL_INTERVAL_SECS)
+        .option(config::OPT_CHANNEL_CHECK_INTERVAL_SECS)
+        .option(config::OPT_CHANNEL_COOLDOWN_SECS)
+        .option(config::OPT_MAX_CONCURRENT_ORDERS)
+        .option(config::OPT_MAX_CHANNEL_FEE_SAT)
+        .option(config::OPT_MAX_CHANNEL_FEE_PERCENT)
+        .option(config::OPT_FALLBACK_MODE)
+        .option(config::OPT_JIT_MIN_AMOUNT_SAT)
+        .option(config::OPT_ENABLE_FALLBACK)
+        .option(config::OPT_ENABLE_CHANNEL_MANAGER)
+        .notification(messages::NotificationTopic::new(
+            invoice_store::INVOICE_PAID_NOTIFICATION,
+        ))
+        .setconfig_callback(setconfig_handler)
+        .rpcmethod(
+            "kickstart-status",
+            "Show ecash balances, pending mint quotes, inbound liquidity and LSP state",
+            status_handler,
+        )
+        .rpcmethod(
+            "kickstart-pay",
+            "Pay a bolt11 invoice with the ecash balance, optionally capping the fee at maxfee sat",
+            pay_handler,
+        )
+        .rpcmethod(
+            "kickstart-send",
+            "Take amount sat out of the ecash wallet as a cashu token, optionally P2PK locked to pubkey",
+            send_handler,
+        )
+        .rpcmethod(
+            "kickstart-receive",
+            "Swap a cashu token into the ecash wallet",
+            receive_handler,
+        )
         .hook("rpc_command", rpc_command_handler)
+        .hook("custommsg", custommsg_handler)
+        .hook("htlc_accepted", htlc_accepted_handler)
+        .subscribe("channel_state_changed", channel_state_changed_handler)
         .with_logging(false)
-        .start(state)
+        .configure()
         .await?
     {
-        info!("Plugin initiated successfully, running...");
-        plugin.join().await?;
+        Some(plugin) => plugin,
+        None => {
+            warn!("Plugin exited");
+            return Ok(());
+        }
+    };
+    // the RPC socket is needed to learn the network before reading the config
+    let rpc_path = Path::new(&configured_plugin.configuration().lightning_dir)
+        .join(configured_plugin.configuration().rpc_file);
+    let config = match async {
+        init_rpc_client(rpc_path)?;
+        Config::from_plugin(&configured_plugin, node_network().await?)
+    }
+    .await
+    {
+        Ok(config) => config,
+        Err(e) => {
+            configured_plugin
+                .disable(&format!("Invalid configuration: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // initialize ecash wallet
+    let wallet = Arc::new(Mutex::new(EcashWallet::new(&config).await?));
+    let lsps0 = Arc::new(Lsps0Transport::default());
+    let lsps2 = match &config.lsps2_node {
+        Some(node) => Some(Arc::new(Lsps2Client::new(
+            node,
+            Arc::clone(&lsps0),
+            config.network.clone(),
+        )?)),
+        None => None,
+    };
+    let state = PluginState {
+        wallet,
+        lsps0,
+        lsps2,
+        config: Arc::new(RwLock::new(config.clone())),
+        channel_status: Arc::new(std::sync::Mutex::new(ChannelManagerStatus::default())),
+        invoices: Arc::new(InvoiceStore::new()),
+        channel_events: Arc::new(watch::channel(()).0),
+    };
+    let plugin = configured_plugin.start(state.clone()).await?;
+    info!("Plugin initiated successfully, running...");
+
+    // JIT invoices handed out before a restart are still paid over their channel
+    if let Some(lsps2) = &state.lsps2 {
+        if let Err(e) = lsps2.load_pending_payments().await {
+            error!("Error loading pending JIT payments: {}", e);
+        }
     }
+    // quotes handed out before a restart are minted as well
+    if let Err(e) = state.wallet.lock().await.load_pending_mint_requests().await {
+        error!("Error loading pending mint quotes: {}", e);
+    }
+    let minting_plugin = plugin.clone();
+    let mint_poll_interval = config.mint_poll_interval;
+    tokio::task::spawn(async move {
+        mint_pending_mint_requests(minting_plugin, mint_poll_interval).await
+    });
+
+    if config.channel_manager_enabled {
+        tokio::task::spawn(async move {
+            let err = channel_manager(state).await;
+            error!("Channel manager exited: {:?}", err);
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+    }
+
+    // run ecash wallet demo
+    // _demo(&mut *wallet.lock().await).await?;
 
+    plugin.join().await?;
     warn!("Plugin exited");
     Ok(())
 }
@@ -90,9 +284,9 @@ async fn _demo(wallet: &mut EcashWallet) -> Result<()> {
     let balance = wallet.get_total_balance().await?;
     debug!("Total balance: {}", balance);
     if balance < 7 {
-        let invoice = wallet.create_lightning_invoice(6).await?;
+        let invoice = wallet.create_lightning_invoice(6, None).await?;
         debug!("Invoice: {}", &invoice.bolt11);
-        while !wallet.check_invoice_status(&invoice.mint_quote_id).await? {
+        while !wallet.check_invoice_status(&invoice).await? {
             tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
         }
         debug!("Invoice paid");
@@ -106,9 +300,9 @@ async fn _demo(wallet: &mut EcashWallet) -> Result<()> {
         stdin.read_line(&mut input).await?;
         input.trim().to_string()
     };
-    let payment_preimage = wallet
-        .pay_lightning_invoice(entered_lightning_invoice)
-        .await?;
-    debug!("Payment preimage: {}", payment_preimage);
+    let (payment_wallet, balance) = wallet.payment_wallet().await?;
+    let melt_result =
+        pay_lightning_invoice(&payment_wallet, balance, entered_lightning_invoice, None).await?;
+    debug!("Payment preimage: {}", melt_result.preimage);
     Ok(())
 }
@@ -0,0 +1,229 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::*;
+
+// NUT-17: mints can push mint quote state changes over a websocket, so we learn about
+// paid fallback invoices instantly instead of polling every quote
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Deserialize)]
+struct WsNotification {
+    params: WsNotificationParams,
+}
+
+#[derive(Debug, Deserialize)]
+struct WsNotificationParams {
+    #[serde(rename = "subId")]
+    sub_id: String,
+    payload: serde_json::Value,
+}
+
+pub struct MintQuoteWatcher {
+    // fired when a mint reports a quote as paid or an invoice is handed out
+    pub changed: Arc<Notify>,
+    // mint url -> its websocket task, started with the first quote of the mint
+    mints: std::sync::Mutex<HashMap<String, MintSubscription>>,
+}
+
+struct MintSubscription {
+    // quote ids with their expiry, the task ends if the mint doesn't support NUT-17
+    quotes: mpsc::UnboundedSender<(String, u64)>,
+    // set while the websocket is up, quotes are only polled otherwise
+    connected: Arc<AtomicBool>,
+}
+
+impl MintQuoteWatcher {
+    pub fn new() -> Self {
+        MintQuoteWatcher {
+            changed: Arc::new(Notify::new()),
+            mints: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    // subscribes to state changes of the quote until it expires, NUT-17 support is probed
+    // by the mint's task so handing out the invoice doesn't wait for it
+    pub fn watch(&self, mint_url: &str, quote_id: &str, expiry: u64) {
+        let mut mints = self.mints.lock().unwrap();
+        let subscription = mints.entry(mint_url.to_string()).or_insert_with(|| {
+            let (quotes, receiver) = mpsc::unbounded_channel();
+            let connected = Arc::new(AtomicBool::new(false));
+            tokio::task::spawn(subscription_task(
+                mint_url.to_string(),
+                receiver,
+                Arc::clone(&self.changed),
+                Arc::clone(&connected),
+            ));
+            MintSubscription { quotes, connected }
+        });
+        // fails for mints without NUT-17, their quotes are polled
+        let _ = subscription.quotes.send((quote_id.to_string(), expiry));
+    }
+
+    // true if all given mints push quote updates, so polling is only a safety net
+    pub fn all_subscribed<'a>(&self, mut mint_urls: impl Iterator<Item = &'a str>) -> bool {
+        let mints = self.mints.lock().unwrap();
+        mint_urls.all(|mint_url| {
+            mints
+                .get(mint_url)
+                .is_some_and(|subscription| subscription.connected.load(Ordering::Relaxed))
+        })
+    }
+}
+
+async fn supports_nut17(mint_url: &str) -> Result<bool> {
+    let info: serde_json::Value =
+        reqwest::get(format!("{}/v1/info", mint_url.trim_end_matches('/')))
+            .await?
+            .json()
+            .await?;
+    Ok(info["nuts"]["17"]["supported"]
+        .as_array()
+        .map(|supported| {
+            supported.iter().any(|method| {
+                method["method"] == "bolt11"
+                    && method["unit"] == "sat"
+                    && method["commands"]
+                        .as_array()
+                        .is_some_and(|commands| commands.iter().any(|c| c == "bolt11_mint_quote"))
+            })
+        })
+        .unwrap_or(false))
+}
+
+// keeps a websocket to the mint open, subscribing every quote id it receives
+// and resubscribing the unexpired ones after a reconnect
+async fn subscription_task(
+    mint_url: String,
+    mut quote_ids: mpsc::UnboundedReceiver<(String, u64)>,
+    changed: Arc<Notify>,
+    connected: Arc<AtomicBool>,
+) {
+    // only a mint answering without NUT-17 is given up on, probe errors are retried
+    let mut retry_delay = Duration::from_secs(1);
+    loop {
+        match supports_nut17(&mint_url).await {
+            Ok(true) => break,
+            Ok(false) => {
+                info!("Mint {} doesn't support NUT-17, polling quotes", mint_url);
+                return;
+            }
+            Err(e) => warn!("Error checking NUT-17 support of {}: {}", mint_url, e),
+        }
+        tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+    let ws_url = format!(
+        "{}/v1/ws",
+        mint_url
+            .trim_end_matches('/')
+            .replacen("https://", "wss://", 1)
+            .replacen("http://", "ws://", 1)
+    );
+    let mut subscribed: Vec<(String, u64)> = Vec::new();
+    let mut reconnect_delay = Duration::from_secs(1);
+    loop {
+        let result = run_subscription(
+            &ws_url,
+            &mut quote_ids,
+            &mut subscribed,
+            &changed,
+            &connected,
+        )
+        .await;
+        connected.store(false, Ordering::Relaxed);
+        match result {
+            // the watcher was dropped
+            Ok(()) => return,
+            Err(e) => warn!("Websocket to {} failed: {}", mint_url, e),
+        }
+        // quotes are still polled in the meantime, so backing off doesn't lose payments
+        tokio::time::sleep(reconnect_delay).await;
+        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn run_subscription(
+    ws_url: &str,
+    quote_ids: &mut mpsc::UnboundedReceiver<(String, u64)>,
+    subscribed: &mut Vec<(String, u64)>,
+    changed: &Notify,
+    connected: &AtomicBool,
+) -> Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(ws_url).await?;
+    debug!("Connected to {}", ws_url);
+    subscribed.retain(|(_, expiry)| *expiry > unix_time());
+    for (quote_id, _) in subscribed.iter() {
+        ws.send(subscribe_message(quote_id)).await?;
+    }
+    connected.store(true, Ordering::Relaxed);
+    loop {
+        let next_expiry = subscribed.iter().map(|(_, expiry)| *expiry).min();
+        let expires_in = next_expiry.map_or(MAX_RECONNECT_DELAY, |expiry| {
+            Duration::from_secs(expiry.saturating_sub(unix_time()))
+        });
+        tokio::select! {
+            quote = quote_ids.recv() => {
+                let Some((quote_id, expiry)) = quote else {
+                    return Ok(());
+                };
+                ws.send(subscribe_message(&quote_id)).await?;
+                subscribed.push((quote_id, expiry));
+            }
+            // expired quotes can't be paid anymore
+            _ = tokio::time::sleep(expires_in) => {
+                let now = unix_time();
+                for (quote_id, _) in subscribed.iter().filter(|(_, expiry)| *expiry <= now) {
+                    ws.send(unsubscribe_message(quote_id)).await?;
+                }
+                subscribed.retain(|(_, expiry)| *expiry > now);
+            }
+            message = ws.next() => {
+                let message = message.ok_or(anyhow!("Connection closed"))??;
+                let WsMessage::Text(text) = message else {
+                    continue;
+                };
+                // subscription confirmations are answers without params and ignored
+                let Ok(notification) = serde_json::from_str::<WsNotification>(&text) else {
+                    continue;
+                };
+                let state = notification.params.payload["state"].as_str().unwrap_or_default();
+                trace!("Mint quote {} is {}", notification.params.sub_id, state);
+                if state == "PAID" || state == "ISSUED" {
+                    subscribed.retain(|(quote_id, _)| *quote_id != notification.params.sub_id);
+                    ws.send(unsubscribe_message(&notification.params.sub_id)).await?;
+                    changed.notify_one();
+                }
+            }
+        }
+    }
+}
+
+// the quote id doubles as subscription id
+fn subscribe_message(quote_id: &str) -> WsMessage {
+    WsMessage::Text(
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "subscribe",
+            "params": {
+                "kind": "bolt11_mint_quote",
+                "subId": quote_id,
+                "filters": [quote_id],
+            }
+        })
+        .to_string(),
+    )
+}
+
+fn unsubscribe_message(quote_id: &str) -> WsMessage {
+    WsMessage::Text(
+        json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "unsubscribe",
+            "params": { "subId": quote_id }
+        })
+        .to_string(),
+    )
+}
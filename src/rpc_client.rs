@@ -0,0 +1,53 @@
+use super::*;
+
+// at most this many idle connections are kept for reuse, busy ones are never shared
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+// the plugin's connections to lightningd's RPC socket, shared by all tasks and hooks, broken
+// connections are dropped and replaced by a new one on the next call
+static RPC_CLIENT: OnceLock<RpcClient> = OnceLock::new();
+
+struct RpcClient {
+    path: PathBuf,
+    idle_connections: std::sync::Mutex<Vec<ClnRpc>>,
+}
+
+// the socket is lightning-dir/rpc-file as given in the init message
+pub fn init_rpc_client(path: PathBuf) -> Result<()> {
+    debug!("Using RPC socket {}", path.display());
+    RPC_CLIENT
+        .set(RpcClient {
+            path,
+            idle_connections: std::sync::Mutex::new(Vec::new()),
+        })
+        .map_err(|_| anyhow!("RPC client already initialized"))
+}
+
+fn rpc_client() -> Result<&'static RpcClient> {
+    RPC_CLIENT
+        .get()
+        .ok_or(anyhow!("RPC client not initialized"))
+}
+
+// every call gets a connection of its own, so slow calls like connect don't hold up the hooks
+pub async fn send_rpc_request(request: Request) -> Result<Response> {
+    let client = rpc_client()?;
+    let idle = client.idle_connections.lock().unwrap().pop();
+    let mut rpc = match idle {
+        Some(rpc) => rpc,
+        None => ClnRpc::new(&client.path).await?,
+    };
+    // a call dropped halfway drops its connection with it, no unread response is left behind
+    let result = rpc.call(request).await;
+    match &result {
+        // errors without code come from the connection, not from lightningd
+        Err(e) if e.code.is_none() => warn!("RPC connection lost, reconnecting: {}", e),
+        _ => {
+            let mut idle_connections = client.idle_connections.lock().unwrap();
+            if idle_connections.len() < MAX_IDLE_CONNECTIONS {
+                idle_connections.push(rpc);
+            }
+        }
+    }
+    Ok(result?)
+}
@@ -0,0 +1,134 @@
+use super::*;
+
+// kickstart-status: everything the plugin currently knows and is doing
+pub async fn status_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let (balances, pending_mint_quotes) = {
+        let wallet = p.state().wallet.lock().await;
+        (
+            wallet.get_balances().await?,
+            wallet.pending_mint_requests().to_vec(),
+        )
+    };
+    let ecash_balance_sat: u64 = balances.iter().map(|(_, balance)| balance).sum();
+    let mints = p.state().config().mints;
+    let receivable = get_receivable_capacity(&ExposePrivateChannels::Auto).await?;
+    let (channel_manager, sats_needed_for_channel) = {
+        let status = p.state().channel_status.lock().unwrap();
+        (
+            serde_json::to_value(&*status)?,
+            status.sats_needed(ecash_balance_sat),
+        )
+    };
+    Ok(json!({
+        "ecash_balance_sat": ecash_balance_sat,
+        "mints": balances
+            .iter()
+            .map(|(mint_url, balance)| {
+                // retired mints have no limit as they only wait for their funds to be moved
+                let mint = mints.iter().find(|mint| mint.url.trim_end_matches('/') == mint_url);
+                json!({
+                    "mint_url": mint_url,
+                    "balance_sat": balance,
+                    "configured": mint.is_some(),
+                    "max_balance_sat": mint.and_then(|mint| mint.max_balance_sat),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "pending_mint_quotes": pending_mint_quotes,
+        "inbound_liquidity_msat": receivable.total_msat,
+        "largest_receivable_msat": receivable.largest_channel_msat,
+        "sats_needed_for_channel": sats_needed_for_channel,
+        "channel_manager": channel_manager,
+    }))
+}
+
+// kickstart-pay bolt11 [maxfee]: pays any invoice with the ecash balance, maxfee in sat
+pub async fn pay_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let params = rpc_params(&v, &["bolt11", "maxfee"], "kickstart-pay bolt11 [maxfee]")?;
+    let bolt11 = params
+        .get("bolt11")
+        .and_then(|bolt11| bolt11.as_str())
+        .ok_or(anyhow!("Usage: kickstart-pay bolt11 [maxfee]"))?
+        .to_string();
+    let max_fee_sat = match params.get("maxfee") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(max_fee) => Some(parse_sat(max_fee).ok_or(anyhow!("Invalid maxfee: {}", max_fee))?),
+    };
+    // the melt blocks until the payment settles, so it runs without the wallet lock
+    let (payment_wallet, balance) = p.state().wallet.lock().await.payment_wallet().await?;
+    let melt_result = pay_lightning_invoice(&payment_wallet, balance, bolt11, max_fee_sat).await;
+    p.state().wallet.lock().await.refresh_balance().await?;
+    let melt_result = melt_result?;
+    Ok(json!({
+        "payment_preimage": melt_result.preimage,
+        "amount_sat": melt_result.amount_sat,
+        "fee_reserve_sat": melt_result.fee_reserve_sat,
+        "fee_paid_sat": melt_result.fee_paid_sat,
+    }))
+}
+
+// kickstart-send amount [pubkey]: exports ecash as cashuB (V4) and cashuA (V3) token
+pub async fn send_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let params = rpc_params(&v, &["amount", "pubkey"], "kickstart-send amount [pubkey]")?;
+    let amount_sat = params
+        .get("amount")
+        .and_then(parse_sat)
+        .ok_or(anyhow!("Usage: kickstart-send amount [pubkey]"))?;
+    let pubkey = params.get("pubkey").and_then(|pubkey| pubkey.as_str());
+    let mut wallet = p.state().wallet.lock().await;
+    let token = wallet.send_token(amount_sat, pubkey).await?;
+    wallet.refresh_balance().await?;
+    Ok(json!({
+        "amount_sat": amount_sat,
+        "p2pk_locked": pubkey.is_some(),
+        "token": token.to_string(),
+        "token_v3": token.to_v3_string(),
+    }))
+}
+
+// kickstart-receive token: swaps a cashu token (V3 or V4) into the wallet
+pub async fn receive_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let params = rpc_params(&v, &["token"], "kickstart-receive token")?;
+    let token = params
+        .get("token")
+        .and_then(|token| token.as_str())
+        .ok_or(anyhow!("Usage: kickstart-receive token"))?;
+    let mut wallet = p.state().wallet.lock().await;
+    let (mint_url, amount_sat) = wallet.receive_token(token.trim()).await?;
+    wallet.refresh_balance().await?;
+    Ok(json!({
+        "mint_url": mint_url,
+        "amount_sat": amount_sat,
+    }))
+}
+
+// rpc params can be passed positional (array) or named (object), unknown names and
+// surplus positional params are refused instead of being ignored
+fn rpc_params(
+    params: &serde_json::Value,
+    names: &[&str],
+    usage: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    named_params(params, names).map_err(|e| anyhow!("{}, usage: {}", e, usage))
+}
+
+// sat amounts as number, "100" or "100sat"
+fn parse_sat(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(amount) => amount.as_u64(),
+        serde_json::Value::String(amount) => amount.trim_end_matches("sat").parse().ok(),
+        _ => None,
+    }
+}